@@ -18,6 +18,17 @@
 //! we wanted to write a DOS emulator, we'd presumably need to choose
 //! different base interrupts, because DOS used interrupt 0x21 for system
 //! calls.
+//!
+//! Everything beyond the offsets is configured through
+//! [`ChainedPicsBuilder`]: automatic EOI, special fully nested mode,
+//! buffered mode and per-line edge/level triggering through the ELCR.
+//! Priority rotation and specific EOIs are runtime commands on
+//! [`ChainedPics`].
+//!
+//! rinuxcore itself uses [`ChainedPics::new`] with offsets 32 and 40, which
+//! is the classic PC/AT setup: 8086 mode, normal (non-automatic) EOI, no
+//! buffering, plain fully nested mode, fixed priorities and the ELCR left as
+//! the firmware programmed it.
 
 #![no_std]
 
@@ -29,9 +40,61 @@ const CMD_INIT: u8 = 0x11;
 /// Command sent to acknowledge an interrupt.
 const CMD_END_OF_INTERRUPT: u8 = 0x20;
 
+/// OCW3 command selecting the Interrupt Request Register for the next read.
+const CMD_READ_IRR: u8 = 0x0A;
+
+/// OCW3 command selecting the In-Service Register for the next read.
+const CMD_READ_ISR: u8 = 0x0B;
+
+/// The line a PIC reports when an interrupt disappears before it is
+/// acknowledged.
+const SPURIOUS_LINE: u8 = 7;
+
+/// OCW2 command acknowledging a specific line, ORed with the line number.
+const CMD_SPECIFIC_END_OF_INTERRUPT: u8 = 0x60;
+
+/// OCW2 command acknowledging an interrupt and rotating priorities so the
+/// acknowledged line becomes the lowest priority.
+const CMD_ROTATE_ON_END_OF_INTERRUPT: u8 = 0xA0;
+
+/// OCW2 command making the given line, ORed in, the lowest priority.
+const CMD_SET_PRIORITY: u8 = 0xC0;
+
+/// OCW2 commands turning rotation in automatic EOI mode on and off.
+const CMD_ROTATE_IN_AUTO_EOI_SET: u8 = 0x80;
+const CMD_ROTATE_IN_AUTO_EOI_CLEAR: u8 = 0x00;
+
 // The mode in which we want to run our PICs.
 const MODE_8086: u8 = 0x01;
 
+/// ICW4 bit: the PIC acknowledges interrupts by itself, no EOI needed.
+const MODE_AUTO_EOI: u8 = 0x02;
+
+/// ICW4 bit: in buffered mode, this PIC is the master.
+const MODE_BUFFERED_MASTER: u8 = 0x04;
+
+/// ICW4 bit: buffered mode, SP/EN drives a bus transceiver.
+const MODE_BUFFERED: u8 = 0x08;
+
+/// ICW4 bit: special fully nested mode.
+const MODE_SPECIAL_FULLY_NESTED: u8 = 0x10;
+
+/// Edge/Level Control Registers, one bit per IRQ, for PIC1 and PIC2.
+const ELCR_PORTS: [u16; 2] = [0x4D0, 0x4D1];
+
+/// IRQs wired internally on PC chipsets that must stay edge triggered:
+/// the timer, keyboard, cascade, RTC and FPU error lines.
+const EDGE_ONLY_LINES: u16 = 1 << 0 | 1 << 1 | 1 << 2 | 1 << 8 | 1 << 13;
+
+/// How an IRQ line signals an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// Interrupt on a rising edge, the ISA default.
+    Edge,
+    /// Interrupt while the line is held high, used by shared PCI lines.
+    Level,
+}
+
 /// An individual PIC chip.  This is not exported, because we always access
 /// it through `Pics` below.
 struct Pic {
@@ -58,6 +121,11 @@ impl Pic {
         self.command.write(CMD_END_OF_INTERRUPT);
     }
 
+    /// Sends an OCW2 command to this PIC.
+    unsafe fn operation(&mut self, command: u8) {
+        self.command.write(command);
+    }
+
     /// Reads the interrupt mask of this PIC.
     unsafe fn read_mask(&mut self) -> u8 {
         self.data.read()
@@ -67,32 +135,174 @@ impl Pic {
     unsafe fn write_mask(&mut self, mask: u8) {
         self.data.write(mask)
     }
+
+    /// Reads the Interrupt Request Register: lines raised but not yet
+    /// delivered to the CPU.
+    unsafe fn read_irr(&mut self) -> u8 {
+        self.command.write(CMD_READ_IRR);
+        self.command.read()
+    }
+
+    /// Reads the In-Service Register: lines delivered to the CPU that have
+    /// not yet been acknowledged.
+    unsafe fn read_isr(&mut self) -> u8 {
+        self.command.write(CMD_READ_ISR);
+        self.command.read()
+    }
+
+    /// A PIC signals a spurious interrupt on its lowest-priority line
+    /// without setting the matching ISR bit.
+    unsafe fn is_spurious(&mut self, interrupt_id: u8) -> bool {
+        interrupt_id == self.offset + SPURIOUS_LINE && self.read_isr() & (1 << SPURIOUS_LINE) == 0
+    }
 }
 
-/// A pair of chained PIC controllers.  This is the standard setup on x86.
-pub struct ChainedPics {
-    pics: [Pic; 2],
+/// Initialization settings for a pair of chained PICs.  Every setting
+/// defaults to what [`ChainedPics::new`] uses.
+///
+/// ```ignore
+/// static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe {
+///     ChainedPics::builder(32, 40)
+///         .trigger_mode(11, TriggerMode::Level)
+///         .build()
+/// });
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainedPicsBuilder {
+    offsets: [u8; 2],
+    auto_eoi: bool,
+    special_fully_nested: bool,
+    buffered: bool,
+    /// ELCR bits to set and to clear during `initialize`; every other line
+    /// keeps what the firmware configured.
+    level_set: u16,
+    level_clear: u16,
 }
 
-impl ChainedPics {
-    /// Create a new interface for the standard PIC1 and PIC2 controllers,
-    /// specifying the desired interrupt offsets.
-    pub const unsafe fn new(offset1: u8, offset2: u8) -> ChainedPics {
+impl ChainedPicsBuilder {
+    /// Start from the default settings with the given interrupt offsets.
+    pub const fn new(offset1: u8, offset2: u8) -> ChainedPicsBuilder {
+        ChainedPicsBuilder {
+            offsets: [offset1, offset2],
+            auto_eoi: false,
+            special_fully_nested: false,
+            buffered: false,
+            level_set: 0,
+            level_clear: 0,
+        }
+    }
+
+    /// Automatic EOI mode: the PICs clear the In-Service bit as soon as the
+    /// CPU acknowledges the interrupt, and `notify_end_of_interrupt` becomes
+    /// a no-op.  Handlers can then be interrupted by their own line.
+    pub const fn auto_eoi(mut self, enabled: bool) -> Self {
+        self.auto_eoi = enabled;
+        self
+    }
+
+    /// Special fully nested mode on PIC1, letting a higher-priority line on
+    /// PIC2 interrupt a handler for a lower-priority one on the same chip.
+    /// `notify_end_of_interrupt` then only acknowledges the cascade line on
+    /// PIC1 once nothing on PIC2 is in service any more.
+    pub const fn special_fully_nested(mut self, enabled: bool) -> Self {
+        self.special_fully_nested = enabled;
+        self
+    }
+
+    /// Buffered mode, for boards where the PICs sit behind a bus
+    /// transceiver.  PIC1 is configured as master and PIC2 as slave.
+    pub const fn buffered(mut self, enabled: bool) -> Self {
+        self.buffered = enabled;
+        self
+    }
+
+    /// Set how an IRQ line (0-15) is triggered.  `initialize` applies this
+    /// on top of the current ELCRs, so lines that are not configured here
+    /// keep their firmware setting.  IRQs 0, 1, 2, 8 and 13 are always edge
+    /// triggered.
+    pub const fn trigger_mode(mut self, irq: u8, mode: TriggerMode) -> Self {
+        assert!(irq < 16, "IRQ line out of range");
+        let bit = 1u16 << irq;
+        match mode {
+            TriggerMode::Edge => {
+                self.level_set &= !bit;
+                self.level_clear |= bit;
+            }
+            TriggerMode::Level => {
+                self.level_set |= bit & !EDGE_ONLY_LINES;
+                self.level_clear &= !bit;
+            }
+        }
+        self
+    }
+
+    /// Whether any trigger mode was configured, so the ELCRs need writing.
+    const fn configures_elcr(&self) -> bool {
+        self.level_set | self.level_clear != 0
+    }
+
+    /// The ELCR contents after applying the configured trigger modes to
+    /// `current`.
+    const fn elcr(&self, current: u16) -> u16 {
+        (current | self.level_set) & !self.level_clear
+    }
+
+    /// The ICW4 byte for PIC1 (`index` 0) or PIC2 (`index` 1).
+    const fn mode(&self, index: usize) -> u8 {
+        let mut mode = MODE_8086;
+        if self.auto_eoi {
+            mode |= MODE_AUTO_EOI;
+        }
+        if self.buffered {
+            mode |= MODE_BUFFERED;
+            if index == 0 {
+                mode |= MODE_BUFFERED_MASTER;
+            }
+        }
+        if self.special_fully_nested && index == 0 {
+            mode |= MODE_SPECIAL_FULLY_NESTED;
+        }
+        mode
+    }
+
+    /// Create the `ChainedPics` these settings describe.  Nothing is sent to
+    /// the hardware until `initialize` is called.
+    pub const unsafe fn build(self) -> ChainedPics {
         ChainedPics {
+            config: self,
             pics: [
                 Pic {
-                    offset: offset1,
+                    offset: self.offsets[0],
                     command: Port::new(0x20),
                     data: Port::new(0x21),
                 },
                 Pic {
-                    offset: offset2,
+                    offset: self.offsets[1],
                     command: Port::new(0xA0),
                     data: Port::new(0xA1),
                 },
             ],
         }
     }
+}
+
+/// A pair of chained PIC controllers.  This is the standard setup on x86.
+pub struct ChainedPics {
+    pics: [Pic; 2],
+    config: ChainedPicsBuilder,
+}
+
+impl ChainedPics {
+    /// Create a new interface for the standard PIC1 and PIC2 controllers,
+    /// specifying the desired interrupt offsets.
+    pub const unsafe fn new(offset1: u8, offset2: u8) -> ChainedPics {
+        ChainedPicsBuilder::new(offset1, offset2).build()
+    }
+
+    /// Start configuring a pair of PICs with modes other than the defaults.
+    pub const fn builder(offset1: u8, offset2: u8) -> ChainedPicsBuilder {
+        ChainedPicsBuilder::new(offset1, offset2)
+    }
 
     /// Initialize both our PICs.  We initialize them together, at the same
     /// time, because it's traditional to do so, and because I/O operations
@@ -133,15 +343,83 @@ impl ChainedPics {
         wait();
 
         // Byte 3: Set our mode.
-        self.pics[0].data.write(MODE_8086);
+        self.pics[0].data.write(self.config.mode(0));
         wait();
-        self.pics[1].data.write(MODE_8086);
+        self.pics[1].data.write(self.config.mode(1));
         wait();
 
+        if self.config.configures_elcr() {
+            let current = self.read_elcr();
+            self.write_elcr(self.config.elcr(current));
+        }
+
         // Restore our saved masks.
         self.write_masks(saved_masks[0], saved_masks[1])
     }
 
+    /// Reads both Edge/Level Control Registers, one bit per IRQ, set for
+    /// level triggered lines.
+    pub unsafe fn read_elcr(&mut self) -> u16 {
+        let mut elcr1: Port<u8> = Port::new(ELCR_PORTS[0]);
+        let mut elcr2: Port<u8> = Port::new(ELCR_PORTS[1]);
+        u16::from_le_bytes([elcr1.read(), elcr2.read()])
+    }
+
+    /// Writes both Edge/Level Control Registers.  Lines that must stay edge
+    /// triggered are cleared regardless of `level_triggered`.
+    unsafe fn write_elcr(&mut self, level_triggered: u16) {
+        let [low, high] = (level_triggered & !EDGE_ONLY_LINES).to_le_bytes();
+        let mut elcr1: Port<u8> = Port::new(ELCR_PORTS[0]);
+        let mut elcr2: Port<u8> = Port::new(ELCR_PORTS[1]);
+        elcr1.write(low);
+        elcr2.write(high);
+    }
+
+    /// How an IRQ line (0-15) is currently triggered, according to the
+    /// ELCR.
+    pub unsafe fn trigger_mode(&mut self, irq: u8) -> TriggerMode {
+        let (pic, bit) = Self::line(irq);
+        if self.read_elcr().to_le_bytes()[pic] & bit != 0 {
+            TriggerMode::Level
+        } else {
+            TriggerMode::Edge
+        }
+    }
+
+    /// Change how an IRQ line (0-15) is triggered.  IRQs 0, 1, 2, 8 and 13
+    /// are always edge triggered.
+    pub unsafe fn set_trigger_mode(&mut self, irq: u8, mode: TriggerMode) {
+        Self::line(irq);
+        let bit = 1u16 << irq;
+        let elcr = self.read_elcr();
+        let elcr = match mode {
+            TriggerMode::Edge => elcr & !bit,
+            TriggerMode::Level => elcr | bit,
+        };
+        self.write_elcr(elcr);
+    }
+
+    /// Turn rotation in automatic EOI mode on or off.  When on, every line
+    /// drops to the lowest priority once it has been serviced, so equal
+    /// priority devices take turns.
+    pub unsafe fn set_rotate_in_auto_eoi(&mut self, enabled: bool) {
+        let command = if enabled {
+            CMD_ROTATE_IN_AUTO_EOI_SET
+        } else {
+            CMD_ROTATE_IN_AUTO_EOI_CLEAR
+        };
+        for pic in self.pics.iter_mut() {
+            pic.operation(command);
+        }
+    }
+
+    /// Make an IRQ line (0-15) the lowest priority on its PIC, which makes
+    /// the next line up the highest.
+    pub unsafe fn set_lowest_priority(&mut self, irq: u8) {
+        let (pic, _) = Self::line(irq);
+        self.pics[pic].operation(CMD_SET_PRIORITY | irq % 8);
+    }
+
     /// Reads the interrupt masks of both PICs.
     pub unsafe fn read_masks(&mut self) -> [u8; 2] {
         [self.pics[0].read_mask(), self.pics[1].read_mask()]
@@ -153,6 +431,46 @@ impl ChainedPics {
         self.pics[1].write_mask(mask2);
     }
 
+    /// Reads the Interrupt Request Registers of both PICs.  Bits 0-7 are
+    /// IRQs 0-7 on PIC1, bits 8-15 are IRQs 8-15 on PIC2.
+    pub unsafe fn read_irr(&mut self) -> u16 {
+        u16::from_le_bytes([self.pics[0].read_irr(), self.pics[1].read_irr()])
+    }
+
+    /// Reads the In-Service Registers of both PICs, laid out like
+    /// `read_irr`.
+    pub unsafe fn read_isr(&mut self) -> u16 {
+        u16::from_le_bytes([self.pics[0].read_isr(), self.pics[1].read_isr()])
+    }
+
+    /// Masks a single IRQ line (0-15), leaving the others untouched.
+    pub unsafe fn mask(&mut self, irq: u8) {
+        let (pic, bit) = Self::line(irq);
+        let mask = self.pics[pic].read_mask();
+        self.pics[pic].write_mask(mask | bit);
+    }
+
+    /// Unmasks a single IRQ line (0-15).  Lines on PIC2 also need the
+    /// cascade line, IRQ 2, unmasked before they reach the CPU.
+    pub unsafe fn unmask(&mut self, irq: u8) {
+        let (pic, bit) = Self::line(irq);
+        let mask = self.pics[pic].read_mask();
+        self.pics[pic].write_mask(mask & !bit);
+    }
+
+    /// Is this IRQ line (0-15) currently masked?
+    pub unsafe fn is_masked(&mut self, irq: u8) -> bool {
+        let (pic, bit) = Self::line(irq);
+        self.pics[pic].read_mask() & bit != 0
+    }
+
+    /// Maps an IRQ line to the index of its PIC and its bit in that PIC's
+    /// registers.
+    fn line(irq: u8) -> (usize, u8) {
+        assert!(irq < 16, "IRQ line out of range: {}", irq);
+        ((irq / 8) as usize, 1 << (irq % 8))
+    }
+
     /// Disables both PICs by masking all interrupts.
     pub unsafe fn disable(&mut self) {
         self.write_masks(u8::MAX, u8::MAX)
@@ -163,15 +481,139 @@ impl ChainedPics {
         self.pics.iter().any(|p| p.handles_interrupt(interrupt_id))
     }
 
+    /// Is this a spurious interrupt?  Both PICs report an interrupt that
+    /// went away before the CPU acknowledged it on their line 7 (IRQ 7 or
+    /// IRQ 15), but leave the corresponding In-Service bit clear.
+    pub unsafe fn is_spurious(&mut self, interrupt_id: u8) -> bool {
+        self.pics.iter_mut().any(|p| p.is_spurious(interrupt_id))
+    }
+
     /// Figure out which (if any) PICs in our chain need to know about this
     /// interrupt.  This is tricky, because all interrupts from `pics[1]`
     /// get chained through `pics[0]`.
+    ///
+    /// Spurious interrupts must not be acknowledged by the PIC that raised
+    /// them.  A spurious IRQ 7 needs no EOI at all, but a spurious IRQ 15
+    /// still went through the cascade on PIC1, so PIC1 alone gets one.
+    ///
+    /// In automatic EOI mode there is nothing to acknowledge.
     pub unsafe fn notify_end_of_interrupt(&mut self, interrupt_id: u8) {
+        if self.config.auto_eoi {
+            return;
+        }
         if self.handles_interrupt(interrupt_id) {
-            if self.pics[1].handles_interrupt(interrupt_id) {
+            if self.pics[0].is_spurious(interrupt_id) {
+                return;
+            }
+            if self.pics[1].handles_interrupt(interrupt_id)
+                && !self.pics[1].is_spurious(interrupt_id)
+            {
                 self.pics[1].end_of_interrupt();
+                if !self.cascade_done() {
+                    return;
+                }
+            }
+            self.pics[0].end_of_interrupt();
+        }
+    }
+
+    /// Whether the cascade line on PIC1 can be acknowledged after an EOI to
+    /// PIC2.  In special fully nested mode PIC2's lines nest inside the one
+    /// cascade interrupt on PIC1, which stays in service until none of them
+    /// is.
+    unsafe fn cascade_done(&mut self) -> bool {
+        !self.config.special_fully_nested || self.pics[1].read_isr() == 0
+    }
+
+    /// Like `notify_end_of_interrupt`, but also rotates priorities so the
+    /// line just serviced becomes the lowest priority on its PIC.
+    pub unsafe fn notify_end_of_interrupt_and_rotate(&mut self, interrupt_id: u8) {
+        if self.config.auto_eoi || !self.handles_interrupt(interrupt_id) {
+            return;
+        }
+        if self.pics[0].is_spurious(interrupt_id) {
+            return;
+        }
+        if self.pics[1].handles_interrupt(interrupt_id) {
+            if !self.pics[1].is_spurious(interrupt_id) {
+                self.pics[1].operation(CMD_ROTATE_ON_END_OF_INTERRUPT);
+                if !self.cascade_done() {
+                    return;
+                }
             }
             self.pics[0].end_of_interrupt();
+        } else {
+            self.pics[0].operation(CMD_ROTATE_ON_END_OF_INTERRUPT);
         }
     }
-}
\ No newline at end of file
+
+    /// Acknowledge a specific IRQ line (0-15) rather than the highest
+    /// priority one in service, as needed in special mask mode.
+    pub unsafe fn notify_specific_end_of_interrupt(&mut self, irq: u8) {
+        let (pic, _) = Self::line(irq);
+        self.pics[pic].operation(CMD_SPECIFIC_END_OF_INTERRUPT | irq % 8);
+        if pic == 1 && self.cascade_done() {
+            // The cascade line on PIC1 is in service as well.
+            self.pics[0].operation(CMD_SPECIFIC_END_OF_INTERRUPT | 2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_mode_is_plain_8086() {
+        let builder = ChainedPics::builder(32, 40);
+        assert_eq!(builder.mode(0), MODE_8086);
+        assert_eq!(builder.mode(1), MODE_8086);
+        assert!(!builder.configures_elcr());
+    }
+
+    #[test]
+    fn modes_go_to_the_right_pic() {
+        let builder = ChainedPics::builder(32, 40)
+            .auto_eoi(true)
+            .buffered(true)
+            .special_fully_nested(true);
+        assert_eq!(
+            builder.mode(0),
+            MODE_8086
+                | MODE_AUTO_EOI
+                | MODE_BUFFERED
+                | MODE_BUFFERED_MASTER
+                | MODE_SPECIAL_FULLY_NESTED
+        );
+        assert_eq!(builder.mode(1), MODE_8086 | MODE_AUTO_EOI | MODE_BUFFERED);
+    }
+
+    #[test]
+    fn trigger_modes_keep_firmware_lines() {
+        // Firmware set IRQs 9, 10 and 11 to level triggered.
+        let firmware = 1 << 9 | 1 << 10 | 1 << 11;
+        let builder = ChainedPics::builder(32, 40)
+            .trigger_mode(5, TriggerMode::Level)
+            .trigger_mode(10, TriggerMode::Edge);
+        assert!(builder.configures_elcr());
+        assert_eq!(builder.elcr(firmware), 1 << 5 | 1 << 9 | 1 << 11);
+    }
+
+    #[test]
+    fn later_trigger_mode_wins() {
+        let builder = ChainedPics::builder(32, 40)
+            .trigger_mode(11, TriggerMode::Edge)
+            .trigger_mode(11, TriggerMode::Level);
+        assert_eq!(builder.elcr(0), 1 << 11);
+        let builder = builder.trigger_mode(11, TriggerMode::Edge);
+        assert_eq!(builder.elcr(1 << 11), 0);
+    }
+
+    #[test]
+    fn edge_only_lines_stay_edge() {
+        let builder = ChainedPics::builder(32, 40)
+            .trigger_mode(0, TriggerMode::Level)
+            .trigger_mode(13, TriggerMode::Level);
+        assert_eq!(builder.elcr(0), 0);
+    }
+}
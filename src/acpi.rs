@@ -0,0 +1,140 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Minimal ACPI table discovery.
+//!
+//! Only what the rest of the kernel needs: finding the RSDP in the BIOS
+//! areas, walking the RSDT/XSDT and handing out tables by signature.
+
+use crate::memory::phys_to_virt;
+use std3::__reexports::x86_64::PhysAddr;
+use std3::{mem, ptr, slice};
+use std3::sync::atomic::{AtomicU64, Ordering};
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// Physical address of the RSDT or XSDT, zero until first looked up.
+static ROOT_TABLE: AtomicU64 = AtomicU64::new(0);
+static ROOT_IS_XSDT: AtomicU64 = AtomicU64::new(0);
+
+/// The header every System Description Table starts with.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SdtHeader {
+    pub(crate) signature: [u8; 4],
+    pub(crate) length: u32,
+    pub(crate) revision: u8,
+    pub(crate) checksum: u8,
+    pub(crate) oem_id: [u8; 6],
+    pub(crate) oem_table_id: [u8; 8],
+    pub(crate) oem_revision: u32,
+    pub(crate) creator_id: u32,
+    pub(crate) creator_revision: u32,
+}
+
+/// A table found through the RSDT/XSDT.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Table {
+    /// Physical address of the table header.
+    pub(crate) address: PhysAddr,
+    /// Total length of the table, header included.
+    pub(crate) length: usize,
+}
+
+impl Table {
+    /// The whole table as bytes, header included.
+    pub(crate) fn bytes(&self) -> &'static [u8] {
+        unsafe { slice::from_raw_parts(phys_to_virt(self.address).as_ptr(), self.length) }
+    }
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+unsafe fn read<T: Copy>(addr: PhysAddr) -> T {
+    ptr::read_unaligned(phys_to_virt(addr).as_ptr::<T>())
+}
+
+fn search_rsdp(start: u64, end: u64) -> Option<PhysAddr> {
+    (start..end).step_by(16).map(PhysAddr::new).find(|addr| {
+        let bytes: &[u8] = unsafe { slice::from_raw_parts(phys_to_virt(*addr).as_ptr(), 20) };
+        &bytes[..8] == RSDP_SIGNATURE && checksum_ok(bytes)
+    })
+}
+
+fn root_table() -> Option<(PhysAddr, bool)> {
+    let cached = ROOT_TABLE.load(Ordering::Acquire);
+    if cached != 0 {
+        return Some((PhysAddr::new(cached), ROOT_IS_XSDT.load(Ordering::Acquire) != 0));
+    }
+
+    let ebda = unsafe { read::<u16>(PhysAddr::new(0x40E)) } as u64 * 16;
+    let rsdp = match ebda {
+        0 => None,
+        ebda => search_rsdp(ebda, ebda + 1024),
+    }
+    .or_else(|| search_rsdp(0xE0000, 0x100000))?;
+
+    let revision: u8 = unsafe { read(rsdp + 15u64) };
+    let (root, is_xsdt) = if revision >= 2 {
+        (unsafe { read::<u64>(rsdp + 24u64) }, true)
+    } else {
+        (unsafe { read::<u32>(rsdp + 16u64) } as u64, false)
+    };
+    if root == 0 {
+        return None;
+    }
+    ROOT_IS_XSDT.store(is_xsdt as u64, Ordering::Release);
+    ROOT_TABLE.store(root, Ordering::Release);
+    Some((PhysAddr::new(root), is_xsdt))
+}
+
+/// Looks up the first table with the given signature, e.g. `b"APIC"` for the MADT.
+pub(crate) fn find_table(signature: &[u8; 4]) -> Option<Table> {
+    let (root, is_xsdt) = root_table()?;
+    let header: SdtHeader = unsafe { read(root) };
+    let entry_size = if is_xsdt { 8 } else { 4 };
+    let entries = (header.length as usize).saturating_sub(mem::size_of::<SdtHeader>()) / entry_size;
+
+    (0..entries)
+        .map(|i| {
+            let entry = root + (mem::size_of::<SdtHeader>() + i * entry_size) as u64;
+            if is_xsdt {
+                PhysAddr::new(unsafe { read::<u64>(entry) })
+            } else {
+                PhysAddr::new(unsafe { read::<u32>(entry) } as u64)
+            }
+        })
+        .map(|address| {
+            let header: SdtHeader = unsafe { read(address) };
+            (address, header)
+        })
+        .find(|(_, header)| &header.signature == signature)
+        .map(|(address, header)| Table {
+            address,
+            length: header.length as usize,
+        })
+        .filter(|table| checksum_ok(table.bytes()))
+}
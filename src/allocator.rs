@@ -25,6 +25,7 @@
 use crate::vga_buffer::print_ok;
 use std3::alloc::{GlobalAlloc, Layout};
 use std3::ptr::null_mut;
+use std3::sync::atomic::{AtomicUsize, Ordering};
 use fixed_size_block::FixedSizeBlockAllocator;
 use std3::__reexports::x86_64;
 use x86_64::{
@@ -74,6 +75,60 @@ pub(crate) fn init_heap(
     Ok(())
 }
 
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static DEALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static FAILED_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static BYTES_IN_USE: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES_IN_USE: AtomicUsize = AtomicUsize::new(0);
+
+/// Counters kept by the kernel heap allocator. Sizes are the requested ones,
+/// not the block sizes handed out.
+#[unstable(feature = "rinuxcore_heap_stats", issue = "none")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Size of the heap in bytes
+    pub heap_size: usize,
+    /// Successful allocations since boot
+    pub allocations: usize,
+    /// Deallocations since boot
+    pub deallocations: usize,
+    /// Allocations that returned null
+    pub failed_allocations: usize,
+    /// Bytes currently allocated
+    pub bytes_in_use: usize,
+    /// Highest value `bytes_in_use` has reached
+    pub peak_bytes_in_use: usize,
+}
+
+/// Returns the current heap counters. Lock-free, so it is safe to call from
+/// a panic handler.
+#[unstable(feature = "rinuxcore_heap_stats", issue = "none")]
+pub fn stats() -> HeapStats {
+    HeapStats {
+        heap_size: HEAP_SIZE,
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+        failed_allocations: FAILED_ALLOCATIONS.load(Ordering::Relaxed),
+        bytes_in_use: BYTES_IN_USE.load(Ordering::Relaxed),
+        peak_bytes_in_use: PEAK_BYTES_IN_USE.load(Ordering::Relaxed),
+    }
+}
+
+fn record_alloc(layout: &Layout, ptr: *mut u8) {
+    if ptr.is_null() {
+        FAILED_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    let in_use = BYTES_IN_USE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+    PEAK_BYTES_IN_USE.fetch_max(in_use, Ordering::Relaxed);
+}
+
+fn record_dealloc(layout: &Layout) {
+    DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    BYTES_IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+}
+
 pub(crate) struct Dummy;
 
 unsafe impl GlobalAlloc for Dummy {
@@ -0,0 +1,253 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Stack traces
+//!
+//! The kernel is built with frame pointers (see `x86_64-rinux.json`), so the
+//! call stack is a linked list of saved `rbp` values, each followed by a
+//! return address. Addresses are resolved against a symbol table that
+//! `tools/ksymgen` generates from the linked kernel ELF and appends to it as
+//! an extra segment, recording where in the `.rinux_ksyms` section.
+//!
+//! The default runner is plain `bootimage runner`, and without the table
+//! backtraces show bare addresses. To get names, install the tool and put it
+//! in front of `bootimage` for your own builds:
+//!
+//! ```text
+//! cargo install --path tools/ksymgen
+//! export CARGO_TARGET_X86_64_RINUX_RUNNER="ksymgen --run bootimage runner"
+//! ```
+
+use crate::memory::is_mapped;
+use crate::{print_err, serial_println};
+use std3::__reexports::x86_64::{structures::idt::InterruptStackFrame, VirtAddr};
+use std3::arch::asm;
+use std3::{fmt, mem, ptr, slice, str};
+
+/// Deepest stack we are willing to walk.
+const MAX_FRAMES: usize = 32;
+
+const KSYMS_MAGIC: &[u8; 8] = b"RKSYMS01";
+/// Bytes per entry: address (u64), size (u32), name offset (u32).
+const ENTRY_SIZE: usize = 16;
+const HEADER_SIZE: usize = 16;
+
+/// Address and length of the symbol table, filled in by `ksymgen` after
+/// linking; zeroes until then. The table holds the magic, the entry count
+/// (u32), the offset of the string table (u32), then entries sorted by
+/// address and NUL-terminated names.
+#[used]
+#[link_section = ".rinux_ksyms"]
+static KSYMS: [u64; 2] = [0; 2];
+
+fn ksyms() -> Option<&'static [u8]> {
+    // Volatile, so the compiler cannot assume it still holds the zeroes it
+    // was declared with.
+    let [address, len] = unsafe { ptr::read_volatile(&KSYMS) };
+    if len < HEADER_SIZE as u64 || !is_mapped(VirtAddr::try_new(address).ok()?) {
+        return None;
+    }
+    let ksyms = unsafe { slice::from_raw_parts(address as *const u8, len as usize) };
+    if &ksyms[..KSYMS_MAGIC.len()] == KSYMS_MAGIC {
+        Some(ksyms)
+    } else {
+        None
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let mut value = [0; mem::size_of::<u32>()];
+    value.copy_from_slice(bytes.get(offset..offset + mem::size_of::<u32>())?);
+    Some(u32::from_le_bytes(value))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    let mut value = [0; mem::size_of::<u64>()];
+    value.copy_from_slice(bytes.get(offset..offset + mem::size_of::<u64>())?);
+    Some(u64::from_le_bytes(value))
+}
+
+/// A resolved code address.
+#[unstable(feature = "rinuxcore_backtrace", issue = "none")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    /// Demangled name of the function containing the address.
+    pub name: &'static str,
+    /// Distance from the start of the function.
+    pub offset: u64,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+/// Looks up the function containing `address` in the embedded symbol table.
+#[unstable(feature = "rinuxcore_backtrace", issue = "none")]
+pub fn symbolize(address: u64) -> Option<Symbol> {
+    let ksyms = ksyms()?;
+    let count = read_u32(ksyms, 8)? as usize;
+    let strings = read_u32(ksyms, 12)? as usize;
+    let entry = |index: usize| {
+        let offset = HEADER_SIZE + index * ENTRY_SIZE;
+        Some((
+            read_u64(ksyms, offset)?,
+            read_u32(ksyms, offset + 8)? as u64,
+            read_u32(ksyms, offset + 12)? as usize,
+        ))
+    };
+
+    // Last entry starting at or before `address`.
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = (low + high) / 2;
+        if entry(middle)?.0 <= address {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    let (start, size, name) = entry(low.checked_sub(1)?)?;
+    if address - start >= size.max(1) {
+        return None;
+    }
+
+    let name = ksyms.get(strings + name..)?;
+    let len = name.iter().position(|byte| *byte == 0)?;
+    Some(Symbol {
+        name: str::from_utf8(&name[..len]).ok()?,
+        offset: address - start,
+    })
+}
+
+/// Calls `f` with each return address on the stack, starting with the
+/// caller of the function whose frame pointer is `rbp`.
+fn walk_from<F: FnMut(u64)>(mut rbp: u64, mut f: F) {
+    for _ in 0..MAX_FRAMES {
+        if rbp == 0 || rbp % 8 != 0 || !frame_readable(rbp) {
+            return;
+        }
+        let (next, return_address) = unsafe {
+            let frame = rbp as *const u64;
+            (frame.read(), frame.add(1).read())
+        };
+        if return_address == 0 {
+            return;
+        }
+        f(return_address);
+        // Stacks grow down, so callers' frames are always further up.
+        if next <= rbp {
+            return;
+        }
+        rbp = next;
+    }
+}
+
+fn frame_readable(rbp: u64) -> bool {
+    match (VirtAddr::try_new(rbp), VirtAddr::try_new(rbp + 8)) {
+        (Ok(frame), Ok(return_address)) => is_mapped(frame) && is_mapped(return_address),
+        _ => false,
+    }
+}
+
+#[inline(always)]
+fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+    rbp
+}
+
+/// Skips `count` frames starting at `rbp`, returning the frame pointer found there.
+fn skip_frames(mut rbp: u64, count: usize) -> Option<u64> {
+    for _ in 0..count {
+        if rbp == 0 || rbp % 8 != 0 || !frame_readable(rbp) {
+            return None;
+        }
+        rbp = unsafe { (rbp as *const u64).read() };
+    }
+    Some(rbp)
+}
+
+/// Calls `f` with every return address on the current call stack, starting
+/// with the caller of `walk`.
+#[unstable(feature = "rinuxcore_backtrace", issue = "none")]
+#[inline(never)]
+pub fn walk<F: FnMut(u64)>(f: F) {
+    walk_from(frame_pointer(), f)
+}
+
+fn print_frame(index: usize, address: u64) {
+    match symbolize(address) {
+        Some(symbol) => {
+            print_err!("  #{:<2} {:#018x} {}\n", index, address, symbol);
+            serial_println!("  #{:<2} {:#018x} {}", index, address, symbol);
+        }
+        None => {
+            print_err!("  #{:<2} {:#018x}\n", index, address);
+            serial_println!("  #{:<2} {:#018x}", index, address);
+        }
+    }
+}
+
+/// Prints the current call stack to the screen and COM1, starting with the
+/// caller of `print`.
+#[unstable(feature = "rinuxcore_backtrace", issue = "none")]
+#[inline(never)]
+pub fn print() {
+    print_err!("Backtrace:\n");
+    serial_println!("Backtrace:");
+    let mut index = 0;
+    walk_from(frame_pointer(), |address| {
+        print_frame(index, address);
+        index += 1;
+    });
+}
+
+/// Prints the call stack of the code an exception interrupted, for use from
+/// `extern "x86-interrupt"` handlers.
+#[inline(never)]
+pub(crate) fn print_fault(stack_frame: &InterruptStackFrame) {
+    print_err!("Backtrace:\n");
+    serial_println!("Backtrace:");
+    let mut index = 0;
+    walk_fault(stack_frame, |address| {
+        print_frame(index, address);
+        index += 1;
+    });
+}
+
+/// Calls `f` with the faulting instruction, then with each return address of
+/// the code an exception interrupted.
+///
+/// Must be inlined into a function the exception handler calls directly: the
+/// frame pointer chain is picked up two frames above it, since the handler's
+/// own frame links to the interrupted function's `rbp`.
+#[inline(always)]
+pub(crate) fn walk_fault<F: FnMut(u64)>(stack_frame: &InterruptStackFrame, mut f: F) {
+    f(stack_frame.instruction_pointer.as_u64());
+    if let Some(rbp) = skip_frames(frame_pointer(), 2) {
+        walk_from(rbp, f);
+    }
+}
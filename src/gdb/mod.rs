@@ -0,0 +1,488 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! GDB remote stub on COM2
+//!
+//! With the `gdb` feature enabled, breakpoint (`int3`) and debug exceptions
+//! stop the kernel and hand control to a debugger speaking the GDB Remote
+//! Serial Protocol on the second serial port. Registers and memory can be
+//! read and written, software breakpoints set and the kernel single-stepped
+//! through the trap flag.
+//!
+//! Run QEMU with `-serial stdio -serial tcp::1234,server,nowait` and attach
+//! with `target remote :1234`. Since there is no COM2 interrupt handler, the
+//! debugger only gets control at a breakpoint; call [`breakpoint`] early on
+//! to stop there and wait for it.
+
+use crate::interrupts::trap::TrapFrame;
+use crate::memory::is_mapped;
+use crate::serial::SERIAL2;
+use crate::vga_buffer::print_ok;
+use packet::{parse_hex, parse_hex_bytes, parse_le, Connection, Response, PACKET_SIZE};
+use std3::__reexports::x86_64;
+use std3::arch::asm;
+use std3::sync::Mutex;
+use x86_64::instructions::segmentation::{Segment, DS, ES, FS, GS};
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::VirtAddr;
+
+mod packet;
+
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xCC;
+const TRAP_FLAG: u64 = 1 << 8;
+/// DR6 bit set when a debug exception was caused by the trap flag.
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+
+/// Register numbers in GDB's amd64 layout, up to and including `gs`.
+const REGISTER_COUNT: usize = 24;
+const RIP: usize = 16;
+const EFLAGS: usize = 17;
+
+static STUB: Mutex<Stub> = Mutex::new(Stub::new());
+
+trap_entry!(gdb_breakpoint_entry => gdb_breakpoint_trap);
+trap_entry!(gdb_debug_entry => gdb_debug_trap);
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    address: u64,
+    original: u8,
+}
+
+/// What to do once the debugger lets the kernel run again.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Resume {
+    Continue,
+    Step,
+    Detach,
+}
+
+struct Stub {
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// A breakpoint lifted to step over it, to be put back at the next debug exception.
+    reinsert: Option<u64>,
+    /// Whether the debugger asked for the next debug exception to stop.
+    stepping: bool,
+    /// Whether a debugger has talked to us since the last detach.
+    attached: bool,
+}
+
+impl Stub {
+    const fn new() -> Stub {
+        Stub {
+            breakpoints: [None; MAX_BREAKPOINTS],
+            reinsert: None,
+            stepping: false,
+            attached: false,
+        }
+    }
+
+    fn breakpoint_at(&self, address: u64) -> Option<Breakpoint> {
+        self.breakpoints
+            .iter()
+            .flatten()
+            .find(|bp| bp.address == address)
+            .copied()
+    }
+
+    fn insert_breakpoint(&mut self, address: u64) -> bool {
+        if self.breakpoint_at(address).is_some() {
+            return true;
+        }
+        let slot = match self.breakpoints.iter_mut().find(|bp| bp.is_none()) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        let original = match read_byte(address) {
+            Some(original) => original,
+            None => return false,
+        };
+        if !write_byte(address, INT3) {
+            return false;
+        }
+        *slot = Some(Breakpoint { address, original });
+        true
+    }
+
+    fn remove_breakpoint(&mut self, address: u64) -> bool {
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|bp| matches!(bp, Some(bp) if bp.address == address));
+        let bp = match slot.and_then(Option::take) {
+            Some(bp) => bp,
+            None => return false,
+        };
+        if self.reinsert == Some(address) {
+            // Already lifted to step over it.
+            self.reinsert = None;
+        } else {
+            write_byte(bp.address, bp.original);
+        }
+        true
+    }
+
+    fn remove_all_breakpoints(&mut self) {
+        let addresses = self.breakpoints;
+        for bp in addresses.iter().flatten() {
+            self.remove_breakpoint(bp.address);
+        }
+    }
+
+    /// Talks to the debugger until it resumes execution, then prepares `frame`.
+    fn enter(&mut self, frame: &mut TrapFrame) {
+        let mut port = SERIAL2.lock();
+        let mut connection = Connection::new(&mut port);
+        if self.attached {
+            connection.send_packet(b"S05");
+        }
+
+        let mut buffer = [0u8; PACKET_SIZE];
+        let resume = loop {
+            let packet = connection.receive_packet(&mut buffer);
+            self.attached = true;
+            let mut response = Response::new();
+            if let Some(resume) = self.handle(packet, frame, &mut response) {
+                if resume == Resume::Detach {
+                    connection.send_packet(response.as_bytes());
+                }
+                break resume;
+            }
+            connection.send_packet(response.as_bytes());
+        };
+
+        if resume == Resume::Detach {
+            self.remove_all_breakpoints();
+            self.attached = false;
+            frame.rflags &= !TRAP_FLAG;
+            return;
+        }
+
+        self.stepping = resume == Resume::Step;
+        // Resuming on top of one of our breakpoints would trap again right
+        // away: lift it for one instruction and put it back afterwards.
+        if let Some(bp) = self.breakpoint_at(frame.rip) {
+            write_byte(bp.address, bp.original);
+            self.reinsert = Some(bp.address);
+        }
+        if self.stepping || self.reinsert.is_some() {
+            frame.rflags |= TRAP_FLAG;
+        } else {
+            frame.rflags &= !TRAP_FLAG;
+        }
+    }
+
+    /// Handles one packet, returning `Some` when execution should resume.
+    fn handle(
+        &mut self,
+        packet: &[u8],
+        frame: &mut TrapFrame,
+        response: &mut Response,
+    ) -> Option<Resume> {
+        let (&command, args) = match packet.split_first() {
+            Some(split) => split,
+            None => return None,
+        };
+        match command {
+            b'?' => response.push_str("S05"),
+            b'g' => {
+                for register in 0..REGISTER_COUNT {
+                    let (value, size) = read_register(frame, register);
+                    response.push_le(value, size);
+                }
+            }
+            b'G' => {
+                let mut rest = args;
+                for register in 0..REGISTER_COUNT {
+                    let size = read_register(frame, register).1 * 2;
+                    if rest.len() < size {
+                        break;
+                    }
+                    if let Some(value) = parse_le(&rest[..size]) {
+                        write_register(frame, register, value);
+                    }
+                    rest = &rest[size..];
+                }
+                response.push_str("OK");
+            }
+            b'p' => match parse_hex(args).filter(|n| (*n as usize) < REGISTER_COUNT) {
+                Some(register) => {
+                    let (value, size) = read_register(frame, register as usize);
+                    response.push_le(value, size);
+                }
+                None => response.push_str("E01"),
+            },
+            b'P' => {
+                let mut parts = args.splitn(2, |byte| *byte == b'=');
+                let register = parts.next().and_then(parse_hex);
+                let value = parts.next().and_then(parse_le);
+                match (register, value) {
+                    (Some(register), Some(value)) if (register as usize) < REGISTER_COUNT => {
+                        write_register(frame, register as usize, value);
+                        response.push_str("OK");
+                    }
+                    _ => response.push_str("E01"),
+                }
+            }
+            b'm' => match parse_address_length(args) {
+                Some((address, length)) => {
+                    let length = length.min((PACKET_SIZE / 2) as u64);
+                    for offset in 0..length {
+                        match read_byte(address.wrapping_add(offset)) {
+                            Some(byte) => response.push_hex(byte),
+                            None => {
+                                // A short read is fine, but not an empty one.
+                                if offset == 0 {
+                                    response.push_str("E14");
+                                }
+                                break;
+                            }
+                        }
+                    }
+                }
+                None => response.push_str("E01"),
+            },
+            b'M' => {
+                let mut parts = args.splitn(2, |byte| *byte == b':');
+                let target = parts.next().and_then(parse_address_length);
+                let mut data = [0u8; PACKET_SIZE / 2];
+                let data_len = parts.next().and_then(|hex| parse_hex_bytes(hex, &mut data));
+                match (target, data_len) {
+                    (Some((address, length)), Some(data_len)) if length as usize == data_len => {
+                        let written = data[..data_len].iter().enumerate().all(|(offset, byte)| {
+                            self.write_memory(address.wrapping_add(offset as u64), *byte)
+                        });
+                        response.push_str(if written { "OK" } else { "E14" });
+                    }
+                    _ => response.push_str("E01"),
+                }
+            }
+            b'Z' | b'z' => {
+                let mut parts = args.split(|byte| *byte == b',');
+                let kind = parts.next();
+                let address = parts.next().and_then(parse_hex);
+                // Only software breakpoints; an empty response declines
+                // hardware breakpoints and watchpoints.
+                if let (Some(b"0"), Some(address)) = (kind, address) {
+                    let done = if command == b'Z' {
+                        self.insert_breakpoint(address)
+                    } else {
+                        self.remove_breakpoint(address)
+                    };
+                    response.push_str(if done { "OK" } else { "E0e" });
+                }
+            }
+            b'c' | b's' => {
+                if let Some(address) = parse_hex(args) {
+                    frame.rip = address;
+                }
+                return Some(if command == b'c' { Resume::Continue } else { Resume::Step });
+            }
+            b'D' => {
+                response.push_str("OK");
+                return Some(Resume::Detach);
+            }
+            b'k' => return Some(Resume::Detach),
+            b'H' => response.push_str("OK"),
+            b'q' if args.starts_with(b"Supported") => {
+                response.push_str("PacketSize=");
+                response.push_hex_number(PACKET_SIZE as u64);
+            }
+            b'q' if args == b"Attached" => response.push_str("1"),
+            b'q' if args == b"C" => response.push_str("QC1"),
+            // An empty response tells the debugger the command is unsupported.
+            _ => {}
+        }
+        None
+    }
+
+    /// Writes a byte on behalf of the debugger, keeping breakpoints intact:
+    /// writes over a breakpoint change the saved original byte instead.
+    fn write_memory(&mut self, address: u64, byte: u8) -> bool {
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .flatten()
+            .find(|bp| bp.address == address);
+        match slot {
+            Some(bp) => {
+                bp.original = byte;
+                true
+            }
+            None => write_byte(address, byte),
+        }
+    }
+}
+
+fn parse_address_length(args: &[u8]) -> Option<(u64, u64)> {
+    let mut parts = args.splitn(2, |byte| *byte == b',');
+    Some((parse_hex(parts.next()?)?, parse_hex(parts.next()?)?))
+}
+
+/// Value and size in bytes of a register in GDB's amd64 numbering.
+fn read_register(frame: &TrapFrame, register: usize) -> (u64, usize) {
+    match register {
+        0 => (frame.rax, 8),
+        1 => (frame.rbx, 8),
+        2 => (frame.rcx, 8),
+        3 => (frame.rdx, 8),
+        4 => (frame.rsi, 8),
+        5 => (frame.rdi, 8),
+        6 => (frame.rbp, 8),
+        7 => (frame.rsp, 8),
+        8 => (frame.r8, 8),
+        9 => (frame.r9, 8),
+        10 => (frame.r10, 8),
+        11 => (frame.r11, 8),
+        12 => (frame.r12, 8),
+        13 => (frame.r13, 8),
+        14 => (frame.r14, 8),
+        15 => (frame.r15, 8),
+        RIP => (frame.rip, 8),
+        EFLAGS => (frame.rflags, 4),
+        18 => (frame.cs, 4),
+        19 => (frame.ss, 4),
+        20 => (DS::get_reg().0 as u64, 4),
+        21 => (ES::get_reg().0 as u64, 4),
+        22 => (FS::get_reg().0 as u64, 4),
+        _ => (GS::get_reg().0 as u64, 4),
+    }
+}
+
+/// Writes a register in GDB's amd64 numbering. Segment registers are left alone.
+fn write_register(frame: &mut TrapFrame, register: usize, value: u64) {
+    match register {
+        0 => frame.rax = value,
+        1 => frame.rbx = value,
+        2 => frame.rcx = value,
+        3 => frame.rdx = value,
+        4 => frame.rsi = value,
+        5 => frame.rdi = value,
+        6 => frame.rbp = value,
+        7 => frame.rsp = value,
+        8 => frame.r8 = value,
+        9 => frame.r9 = value,
+        10 => frame.r10 = value,
+        11 => frame.r11 = value,
+        12 => frame.r12 = value,
+        13 => frame.r13 = value,
+        14 => frame.r14 = value,
+        15 => frame.r15 = value,
+        RIP => frame.rip = value,
+        EFLAGS => frame.rflags = frame.rflags & !0xFFFF_FFFF | value & 0xFFFF_FFFF,
+        _ => {}
+    }
+}
+
+fn read_byte(address: u64) -> Option<u8> {
+    let address = VirtAddr::try_new(address).ok()?;
+    if !is_mapped(address) {
+        return None;
+    }
+    Some(unsafe { address.as_ptr::<u8>().read_volatile() })
+}
+
+/// Writes a byte even if the page is read-only, as kernel code is.
+fn write_byte(address: u64, byte: u8) -> bool {
+    let address = match VirtAddr::try_new(address) {
+        Ok(address) if is_mapped(address) => address,
+        _ => return false,
+    };
+    unsafe {
+        let cr0 = Cr0::read();
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        address.as_mut_ptr::<u8>().write_volatile(byte);
+        Cr0::write(cr0);
+    }
+    true
+}
+
+#[no_mangle]
+extern "C" fn gdb_breakpoint_trap(frame: &mut TrapFrame) {
+    crate::interrupts::stats::record(3);
+    // Nobody attaches to a test run; `int3` in tests just continues.
+    if crate::running_tests() {
+        return;
+    }
+    let mut stub = STUB.lock();
+    // `int3` leaves RIP after itself; point it back at the breakpoint so the
+    // debugger sees the address it asked for.
+    if stub.breakpoint_at(frame.rip.wrapping_sub(1)).is_some() {
+        frame.rip -= 1;
+    }
+    stub.enter(frame);
+}
+
+#[no_mangle]
+extern "C" fn gdb_debug_trap(frame: &mut TrapFrame) {
+    crate::interrupts::stats::record(1);
+    let dr6: u64;
+    unsafe {
+        asm!("mov {}, dr6", out(reg) dr6, options(nomem, nostack));
+        asm!("mov dr6, {}", in(reg) 0u64, options(nomem, nostack));
+    }
+
+    let mut stub = STUB.lock();
+    frame.rflags &= !TRAP_FLAG;
+    if dr6 & DR6_SINGLE_STEP == 0 {
+        return;
+    }
+    if let Some(address) = stub.reinsert.take() {
+        if stub.breakpoint_at(address).is_some() {
+            write_byte(address, INT3);
+        }
+    }
+    if stub.stepping {
+        stub.stepping = false;
+        stub.enter(frame);
+    }
+}
+
+/// Points the breakpoint and debug exception vectors at the stub.
+pub(crate) fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.breakpoint
+            .set_handler_addr(VirtAddr::from_ptr(gdb_breakpoint_entry as *const ()));
+        idt.debug
+            .set_handler_addr(VirtAddr::from_ptr(gdb_debug_entry as *const ()));
+    }
+}
+
+pub(crate) fn init() {
+    // Force the port to be set up before the first trap needs it.
+    let _ = SERIAL2.lock();
+    unsafe {
+        if !crate::CONFIG.quiet_boot {
+            print_ok!("[OK] GDB stub listening on COM2\n");
+        }
+    }
+}
+
+/// Stops in the debugger, or waits for one to attach.
+#[unstable(feature = "rinuxcore_gdb", issue = "none")]
+pub fn breakpoint() {
+    x86_64::instructions::interrupts::int3();
+}
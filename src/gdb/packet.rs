@@ -0,0 +1,177 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Remote Serial Protocol framing: `$payload#checksum`, acknowledged with
+//! `+` or `-`.
+
+use std3::__reexports::uart_16550::SerialPort;
+
+/// Largest packet we accept or send, advertised through `qSupported`.
+pub(super) const PACKET_SIZE: usize = 1024;
+
+/// A packet payload being built up for sending.
+pub(super) struct Response {
+    buffer: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Response {
+    pub(super) fn new() -> Response {
+        Response {
+            buffer: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    pub(super) fn push(&mut self, byte: u8) {
+        if self.len < PACKET_SIZE {
+            self.buffer[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    pub(super) fn push_str(&mut self, s: &str) {
+        s.bytes().for_each(|byte| self.push(byte));
+    }
+
+    /// Appends `byte` as two lowercase hex digits.
+    pub(super) fn push_hex(&mut self, byte: u8) {
+        self.push(HEX_DIGITS[(byte >> 4) as usize]);
+        self.push(HEX_DIGITS[(byte & 0xF) as usize]);
+    }
+
+    /// Appends `value` as a big endian hex number without leading zeros.
+    pub(super) fn push_hex_number(&mut self, value: u64) {
+        let digits = ((64 - value.leading_zeros() as usize + 3) / 4).max(1);
+        for digit in (0..digits).rev() {
+            self.push(HEX_DIGITS[(value >> (digit * 4)) as usize & 0xF]);
+        }
+    }
+
+    /// Appends the low `size` bytes of `value` in target (little endian) order.
+    pub(super) fn push_le(&mut self, value: u64, size: usize) {
+        value.to_le_bytes()[..size].iter().for_each(|byte| self.push_hex(*byte));
+    }
+
+    pub(super) fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+pub(super) fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parses a big endian hex number, as used for addresses and lengths.
+pub(super) fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits
+        .iter()
+        .try_fold(0u64, |value, digit| Some(value << 4 | hex_value(*digit)? as u64))
+}
+
+/// Parses pairs of hex digits into `out`, returning how many bytes were written.
+pub(super) fn parse_hex_bytes(digits: &[u8], out: &mut [u8]) -> Option<usize> {
+    if digits.len() % 2 != 0 || digits.len() / 2 > out.len() {
+        return None;
+    }
+    for (i, pair) in digits.chunks(2).enumerate() {
+        out[i] = hex_value(pair[0])? << 4 | hex_value(pair[1])?;
+    }
+    Some(digits.len() / 2)
+}
+
+/// Parses a little endian hex value of up to eight bytes, as used for registers.
+pub(super) fn parse_le(digits: &[u8]) -> Option<u64> {
+    let mut bytes = [0u8; 8];
+    parse_hex_bytes(digits, &mut bytes)?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// The debugger's end of COM2.
+pub(super) struct Connection<'a> {
+    port: &'a mut SerialPort,
+}
+
+impl<'a> Connection<'a> {
+    pub(super) fn new(port: &'a mut SerialPort) -> Connection<'a> {
+        Connection { port }
+    }
+
+    /// Waits for the next well-formed packet and returns its payload.
+    pub(super) fn receive_packet<'b>(&mut self, buffer: &'b mut [u8; PACKET_SIZE]) -> &'b [u8] {
+        loop {
+            // Skip acknowledgements, interrupt requests and line noise.
+            while self.port.receive() != b'$' {}
+
+            let mut len = 0;
+            let mut checksum = 0u8;
+            let mut byte = self.port.receive();
+            while byte != b'#' {
+                if len < PACKET_SIZE {
+                    buffer[len] = byte;
+                    len += 1;
+                }
+                checksum = checksum.wrapping_add(byte);
+                byte = self.port.receive();
+            }
+
+            let high = hex_value(self.port.receive());
+            let low = hex_value(self.port.receive());
+            match (high, low) {
+                (Some(high), Some(low)) if high << 4 | low == checksum && len < PACKET_SIZE => {
+                    self.port.send(b'+');
+                    return &buffer[..len];
+                }
+                _ => self.port.send(b'-'),
+            }
+        }
+    }
+
+    /// Sends a packet, retransmitting until the debugger acknowledges it.
+    pub(super) fn send_packet(&mut self, payload: &[u8]) {
+        let checksum = payload.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        loop {
+            self.port.send(b'$');
+            payload.iter().for_each(|byte| self.port.send(*byte));
+            self.port.send(b'#');
+            self.port.send(HEX_DIGITS[(checksum >> 4) as usize]);
+            self.port.send(HEX_DIGITS[(checksum & 0xF) as usize]);
+
+            match self.port.receive() {
+                b'-' => continue,
+                _ => return,
+            }
+        }
+    }
+}
@@ -22,59 +22,115 @@
 // SOFTWARE.
 //
 
+use crate::memory;
 use crate::vga_buffer::print_ok;
 use std3::__reexports::x86_64;
-use std3::lazy_static;
+use std3::{boxed::Box, lazy_static};
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub(crate) const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub(crate) const NMI_IST_INDEX: u16 = 1;
+pub(crate) const MACHINE_CHECK_IST_INDEX: u16 = 2;
 pub(crate) const STACK_SIZE: usize = 1024 * 20; // 20480
 
+/// Top of a fresh, statically allocated interrupt stack.
+macro_rules! ist_stack {
+    () => {{
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        stack_start + STACK_SIZE
+    }};
+}
+
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            stack_start + STACK_SIZE
-        };
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = ist_stack!();
+        // NMIs and machine checks can arrive at any instruction, including
+        // while the current stack is unusable.
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = ist_stack!();
+        tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = ist_stack!();
+        // Loaded into RSP whenever an interrupt or fault arrives in ring 3.
+        tss.privilege_stack_table[0] = ist_stack!();
         tss
     };
 }
 
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = build(&TSS);
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// The segment order is fixed by SYSCALL/SYSRET: kernel data must follow
+/// kernel code, and user code must follow user data. Every CPU gets the same
+/// layout, so the selectors are the same everywhere.
+fn build(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            data_selector,
+            user_data_selector,
+            user_code_selector,
+            tss_selector,
+        },
+    )
 }
 
-pub(crate) fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
-    use x86_64::instructions::tables::load_tss;
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Selectors {
+    pub(crate) code_selector: SegmentSelector,
+    pub(crate) data_selector: SegmentSelector,
+    pub(crate) user_data_selector: SegmentSelector,
+    pub(crate) user_code_selector: SegmentSelector,
+    pub(crate) tss_selector: SegmentSelector,
+}
+
+pub(crate) fn selectors() -> &'static Selectors {
+    &GDT.1
+}
 
-    GDT.0.load();
+pub(crate) fn init() {
+    load(&GDT);
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
         if !crate::CONFIG.quiet_boot {
             print_ok!("[OK] GDT initialized\n");
         }
     }
 }
+
+/// Gives an application processor its own TSS, with freshly mapped interrupt
+/// stacks, and a GDT pointing at it.
+pub(crate) fn init_ap() -> Result<(), MapToError<Size4KiB>> {
+    let mut tss = TaskStateSegment::new();
+    for index in [DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX, MACHINE_CHECK_IST_INDEX] {
+        tss.interrupt_stack_table[index as usize] = memory::alloc_stack(STACK_SIZE as u64)?;
+    }
+    tss.privilege_stack_table[0] = memory::alloc_stack(STACK_SIZE as u64)?;
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+    load(Box::leak(Box::new(build(tss))));
+    Ok(())
+}
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+    use x86_64::instructions::tables::load_tss;
+
+    gdt.0.load();
+    unsafe {
+        CS::set_reg(gdt.1.code_selector);
+        SS::set_reg(gdt.1.data_selector);
+        DS::set_reg(gdt.1.data_selector);
+        ES::set_reg(gdt.1.data_selector);
+        load_tss(gdt.1.tss_selector);
+    }
+}
@@ -22,12 +22,25 @@
 // SOFTWARE.
 //
 
-use crate::{gdt, hlt_loop, print_err, vga_buffer::print_ok};
+use crate::per_cpu::KernelGs;
+use crate::userspace::{self, UserExit};
+use crate::{gdt, hlt_loop, print_err, serial_println, vga_buffer::print_ok};
 use std3::__reexports::x86_64;
 use std3::lazy_static;
 use pic8259::ChainedPics;
 use std3::sync as spin;
+use trap::TrapFrame;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+#[macro_use]
+pub(crate) mod trap;
+pub(crate) mod lapic;
+pub(crate) mod mce;
+#[unstable(feature = "rinuxcore_nmi", issue = "none")]
+pub mod nmi;
+#[unstable(feature = "rinuxcore_interrupt_stats", issue = "none")]
+pub mod stats;
 
 pub(crate) const PIC_1_OFFSET: u8 = 32;
 pub(crate) const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -37,6 +50,9 @@ pub(crate) const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub(crate) enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    SpuriousMaster = PIC_1_OFFSET + 7,
+    Rtc = PIC_2_OFFSET,
+    SpuriousSlave = PIC_2_OFFSET + 7,
 }
 
 impl InterruptIndex {
@@ -56,20 +72,40 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        #[cfg(feature = "gdb")]
+        crate::gdb::install(&mut idt);
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt
+                .set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check
+                .set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        }
+        unsafe {
+            idt[InterruptIndex::Timer.as_usize()]
+                .set_handler_addr(VirtAddr::from_ptr(timer_entry as *const ()));
         }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Rtc.as_usize()].set_handler_fn(rtc_interrupt_handler);
+        idt[InterruptIndex::SpuriousMaster.as_usize()].set_handler_fn(spurious_master_handler);
+        idt[InterruptIndex::SpuriousSlave.as_usize()].set_handler_fn(spurious_slave_handler);
+        idt[lapic::SPURIOUS_VECTOR as usize].set_handler_fn(lapic_spurious_handler);
+        idt[lapic::WAKEUP_VECTOR as usize].set_handler_fn(wakeup_handler);
         idt
     };
 }
 
 pub(crate) fn init_idt() {
-    IDT.load();
+    load_idt();
     unsafe {
         if !crate::CONFIG.quiet_boot {
             print_ok!("[OK] IDT initialized\n");
@@ -77,7 +113,14 @@ pub(crate) fn init_idt() {
     }
 }
 
+/// Loads the IDT on the running CPU.
+pub(crate) fn load_idt() {
+    IDT.load();
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(3);
     print_err!("[FAIL] BREAKPOINT\n{:#?}\n", stack_frame);
 }
 
@@ -86,11 +129,68 @@ extern "x86-interrupt" fn page_fault_handler(
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(14);
+
+    if userspace::from_user(&stack_frame) {
+        let exit = UserExit::PageFault {
+            address: Cr2::read().as_u64(),
+            error_code: error_code.bits(),
+        };
+        unsafe { userspace::return_to_kernel(exit, &stack_frame) };
+    }
 
     print_err!("[FAIL] PAGE FAULT\n");
     print_err!("[FAIL] Accessed Address: {:?}\n", Cr2::read());
     print_err!("[FAIL] Error Code: {:?}\n", error_code);
     print_err!("[FAIL] {:#?}\n", stack_frame);
+    crate::backtrace::print_fault(&stack_frame);
+    crate::panic::fault_screen(
+        "PAGE FAULT",
+        &stack_frame,
+        Some(Cr2::read().as_u64()),
+        format_args!("{:?}", error_code),
+    );
+    hlt_loop();
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    exception(stack_frame, 0, "DIVIDE ERROR", None);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    exception(stack_frame, 6, "INVALID OPCODE", None);
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    exception(stack_frame, 12, "STACK SEGMENT FAULT", Some(error_code));
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    exception(stack_frame, 13, "GENERAL PROTECTION FAULT", Some(error_code));
+}
+
+/// Hands exceptions raised in user mode back to `userspace::run`; in the
+/// kernel they are fatal.
+#[inline(always)]
+fn exception(stack_frame: InterruptStackFrame, vector: u8, kind: &str, error_code: Option<u64>) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(vector);
+    if userspace::from_user(&stack_frame) {
+        let exit = UserExit::Exception { vector, error_code };
+        unsafe { userspace::return_to_kernel(exit, &stack_frame) };
+    }
+    print_err!("[FAIL] {}\n", kind);
+    print_err!("[FAIL] Error Code: {:?}\n", error_code);
+    print_err!("[FAIL] {:#?}\n", stack_frame);
+    crate::backtrace::print_fault(&stack_frame);
+    crate::panic::fault_screen(kind, &stack_frame, None, format_args!("{:?}", error_code));
     hlt_loop();
 }
 
@@ -98,18 +198,63 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    stats::record(8);
     print_err!("[FAIL] DOUBLE FAULT\n{:#?}\n", stack_frame);
+    crate::backtrace::print_fault(&stack_frame);
+    crate::panic::fault_screen("DOUBLE FAULT", &stack_frame, None, format_args!("#DF"));
     panic!("[FAIL] DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    stats::record(2);
+    crate::smp::stop_requested();
+    if nmi::handle(&stack_frame) {
+        return;
+    }
+    let status = nmi::hardware_error().unwrap_or(0);
+    crate::panic::take_console();
+    print_err!("[FAIL] NMI: hardware error, port 0x61 = {:#04x}\n", status);
+    serial_println!("[FAIL] NMI: hardware error, port 0x61 = {:#04x}", status);
+    crate::panic::fault_screen(
+        "NON-MASKABLE INTERRUPT",
+        &stack_frame,
+        None,
+        format_args!("hardware error, port 0x61 = {:#04x}", status),
+    );
+    hlt_loop();
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    stats::record(18);
+    crate::panic::take_console();
+    print_err!("[FAIL] MACHINE CHECK\n{:#?}\n", stack_frame);
+    serial_println!("[FAIL] MACHINE CHECK\n{:#?}", stack_frame);
+    mce::report();
+    crate::panic::fault_screen("MACHINE CHECK", &stack_frame, None, format_args!("#MC"));
+    hlt_loop();
+}
+
+trap_entry!(timer_entry => timer_trap);
+
+/// The timer goes through a full register save so it can take the CPU back
+/// from a user program, which the scheduler then resumes later.
+#[no_mangle]
+extern "C" fn timer_trap(frame: &mut TrapFrame) {
+    let vector = InterruptIndex::Timer.as_u8();
+    stats::record(vector);
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+        PICS.lock().notify_end_of_interrupt(vector);
     }
+    userspace::scheduler::tick();
+    if userspace::trap_from_user(frame) {
+        unsafe { userspace::return_from_trap(UserExit::Interrupt { vector }, frame) };
+    }
+    crate::thread::preempt();
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(InterruptIndex::Keyboard.as_u8());
     use x86_64::instructions::port::Port;
 
     let mut port = Port::new(0x60);
@@ -122,6 +267,60 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
+extern "x86-interrupt" fn rtc_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    stats::record(InterruptIndex::Rtc.as_u8());
+    crate::time::rtc::handle_interrupt();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Rtc.as_u8());
+    }
+}
+
+/// IRQ7 is where PIC1 reports spurious interrupts; `notify_end_of_interrupt`
+/// only acknowledges it if it turns out to be real.
+extern "x86-interrupt" fn spurious_master_handler(_stack_frame: InterruptStackFrame) {
+    spurious_interrupt(InterruptIndex::SpuriousMaster);
+}
+
+/// Same as `spurious_master_handler` for IRQ15 on PIC2.
+extern "x86-interrupt" fn spurious_slave_handler(_stack_frame: InterruptStackFrame) {
+    spurious_interrupt(InterruptIndex::SpuriousSlave);
+}
+
+/// Only there to end a `hlt`; the woken CPU finds its work itself.
+extern "x86-interrupt" fn wakeup_handler(_stack_frame: InterruptStackFrame) {
+    stats::record(lapic::WAKEUP_VECTOR);
+    lapic::end_of_interrupt();
+}
+
+/// The local APIC does not expect an end of interrupt for these.
+extern "x86-interrupt" fn lapic_spurious_handler(_stack_frame: InterruptStackFrame) {
+    stats::record(lapic::SPURIOUS_VECTOR);
+    stats::record_spurious();
+}
+
+fn spurious_interrupt(index: InterruptIndex) {
+    stats::record(index.as_u8());
+    unsafe {
+        let mut pics = PICS.lock();
+        if pics.is_spurious(index.as_u8()) {
+            stats::record_spurious();
+        }
+        pics.notify_end_of_interrupt(index.as_u8());
+    }
+}
+
+/// Unmasks IRQ8 on the slave PIC, and the cascade line it is chained through.
+pub(crate) fn enable_rtc_irq() {
+    unsafe {
+        let mut pics = PICS.lock();
+        pics.unmask(2);
+        pics.unmask(8);
+    }
+}
+
 #[test_case]
 fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
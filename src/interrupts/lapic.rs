@@ -0,0 +1,136 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! The local APIC, as far as the kernel uses it: identifying the running CPU
+//! and sending inter-processor interrupts. Device interrupts still come
+//! through the 8259 PICs.
+
+use crate::memory;
+use std3::__reexports::x86_64::{instructions::interrupts, PhysAddr};
+use std3::ptr;
+use std3::sync::atomic::{AtomicU64, Ordering};
+
+const ID: u64 = 0x20;
+const END_OF_INTERRUPT: u64 = 0xB0;
+const SPURIOUS: u64 = 0xF0;
+const ERROR_STATUS: u64 = 0x280;
+const ICR_LOW: u64 = 0x300;
+const ICR_HIGH: u64 = 0x310;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const DELIVERY_PENDING: u32 = 1 << 12;
+const INIT: u32 = 0b101 << 8 | 1 << 14;
+const STARTUP: u32 = 0b110 << 8 | 1 << 14;
+const FIXED: u32 = 1 << 14;
+const NMI: u32 = 0b100 << 8 | 1 << 14;
+const ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+/// Vector of the local APIC's spurious interrupt, which must not be
+/// acknowledged.
+pub(crate) const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Vector of the IPI that wakes a halted CPU because work arrived for it.
+pub(crate) const WAKEUP_VECTOR: u8 = 0xF0;
+
+/// Virtual address of the register block, zero until `init`.
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Maps the register block at `address` and enables the bootstrap
+/// processor's local APIC. Returns `false` if it could not be mapped.
+pub(crate) fn init(address: PhysAddr) -> bool {
+    match memory::map_mmio(address, 4096) {
+        Ok(virt) => {
+            BASE.store(virt.as_u64(), Ordering::Release);
+            enable();
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+unsafe fn read(register: u64) -> u32 {
+    ptr::read_volatile((BASE.load(Ordering::Acquire) + register) as *const u32)
+}
+
+unsafe fn write(register: u64, value: u32) {
+    ptr::write_volatile((BASE.load(Ordering::Acquire) + register) as *mut u32, value)
+}
+
+/// Software-enables the running CPU's local APIC.
+pub(crate) fn enable() {
+    unsafe {
+        let spurious = read(SPURIOUS) & !0xFF;
+        write(SPURIOUS, spurious | SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+}
+
+/// APIC ID of the running CPU, 0 before `init`.
+pub(crate) fn id() -> u8 {
+    if BASE.load(Ordering::Acquire) == 0 {
+        return 0;
+    }
+    unsafe { (read(ID) >> 24) as u8 }
+}
+
+/// Sends an INIT IPI, resetting the target into its wait-for-SIPI state.
+pub(crate) fn send_init(apic_id: u8) {
+    send(apic_id, INIT);
+}
+
+/// Sends a STARTUP IPI; the target starts in real mode at `page * 4096`.
+pub(crate) fn send_startup(apic_id: u8, page: u8) {
+    send(apic_id, STARTUP | page as u32);
+}
+
+/// Sends a fixed IPI with `vector` to one CPU.
+pub(crate) fn send_ipi(apic_id: u8, vector: u8) {
+    send(apic_id, FIXED | vector as u32);
+}
+
+/// Sends an NMI to every CPU but the running one. Does nothing before
+/// `init`.
+pub(crate) fn send_nmi_to_others() {
+    if BASE.load(Ordering::Acquire) != 0 {
+        send(0, NMI | ALL_EXCLUDING_SELF);
+    }
+}
+
+/// Acknowledges the interrupt being handled, for vectors delivered by the
+/// local APIC rather than the PICs.
+pub(crate) fn end_of_interrupt() {
+    unsafe { write(END_OF_INTERRUPT, 0) };
+}
+
+/// Interrupts are disabled throughout, so an IPI sent from a handler cannot
+/// retarget this one between the two ICR writes.
+fn send(apic_id: u8, command: u32) {
+    interrupts::without_interrupts(|| unsafe {
+        write(ERROR_STATUS, 0);
+        write(ICR_HIGH, (apic_id as u32) << 24);
+        write(ICR_LOW, command);
+        while read(ICR_LOW) & DELIVERY_PENDING != 0 {
+            std3::hint::spin_loop();
+        }
+    });
+}
@@ -0,0 +1,126 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Machine check architecture: enabling it at boot and decoding the bank
+//! registers when a machine check exception arrives.
+
+use crate::{print_err, serial_println};
+use std3::__reexports::x86_64;
+use std3::arch::x86_64::__cpuid;
+use std3::sync::atomic::{AtomicU8, Ordering};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17A;
+const IA32_MCG_CTL: u32 = 0x17B;
+const IA32_MC0_CTL: u32 = 0x400;
+
+const MCG_CTL_P: u64 = 1 << 8;
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+
+const STATUS_VAL: u64 = 1 << 63;
+const STATUS_OVER: u64 = 1 << 62;
+const STATUS_UC: u64 = 1 << 61;
+const STATUS_EN: u64 = 1 << 60;
+const STATUS_MISCV: u64 = 1 << 59;
+const STATUS_ADDRV: u64 = 1 << 58;
+const STATUS_PCC: u64 = 1 << 57;
+
+static BANKS: AtomicU8 = AtomicU8::new(0);
+
+fn bank_msr(bank: u8, register: u32) -> Msr {
+    Msr::new(IA32_MC0_CTL + 4 * u32::from(bank) + register)
+}
+
+/// Checks CPUID for MCE and MCA support. Both are needed to report anything.
+fn supported() -> bool {
+    let edx = unsafe { __cpuid(1) }.edx;
+    edx & (1 << 7) != 0 && edx & (1 << 14) != 0
+}
+
+/// Enables every reporting bank, clears stale errors left over from before
+/// the reset, and sets CR4.MCE. Returns the number of banks, or `None` if
+/// the CPU has no machine check architecture.
+pub(crate) fn init() -> Option<u8> {
+    if !supported() {
+        return None;
+    }
+    unsafe {
+        let cap = Msr::new(IA32_MCG_CAP).read();
+        let banks = cap as u8;
+        if cap & MCG_CTL_P != 0 {
+            Msr::new(IA32_MCG_CTL).write(u64::MAX);
+        }
+        for bank in 0..banks {
+            bank_msr(bank, 0).write(u64::MAX);
+            bank_msr(bank, 1).write(0);
+        }
+        BANKS.store(banks, Ordering::Relaxed);
+        Cr4::update(|flags| flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION));
+        Some(banks)
+    }
+}
+
+macro_rules! report {
+    ($($arg:tt)*) => {{
+        print_err!("{}\n", format_args!($($arg)*));
+        serial_println!($($arg)*);
+    }};
+}
+
+/// Prints `IA32_MCG_STATUS` and every bank holding a valid error.
+pub(crate) fn report() {
+    let status = unsafe { Msr::new(IA32_MCG_STATUS).read() };
+    report!(
+        "[FAIL] MCG_STATUS {:#x} (RIPV={} EIPV={})",
+        status,
+        status & MCG_STATUS_RIPV != 0,
+        status & MCG_STATUS_EIPV != 0
+    );
+    for bank in 0..BANKS.load(Ordering::Relaxed) {
+        let status = unsafe { bank_msr(bank, 1).read() };
+        if status & STATUS_VAL == 0 {
+            continue;
+        }
+        report!(
+            "[FAIL] bank {}: status {:#018x} mca {:#06x} model {:#06x}{}{}{}{}",
+            bank,
+            status,
+            status & 0xFFFF,
+            (status >> 16) & 0xFFFF,
+            if status & STATUS_UC != 0 { " UC" } else { "" },
+            if status & STATUS_PCC != 0 { " PCC" } else { "" },
+            if status & STATUS_OVER != 0 { " OVER" } else { "" },
+            if status & STATUS_EN != 0 { " EN" } else { "" }
+        );
+        if status & STATUS_ADDRV != 0 {
+            report!("[FAIL]   addr {:#018x}", unsafe { bank_msr(bank, 2).read() });
+        }
+        if status & STATUS_MISCV != 0 {
+            report!("[FAIL]   misc {:#018x}", unsafe { bank_msr(bank, 3).read() });
+        }
+    }
+}
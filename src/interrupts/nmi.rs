@@ -0,0 +1,87 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Non-maskable interrupt hook.
+//!
+//! NMIs run on their own IST stack and can interrupt any instruction,
+//! including code that holds a lock or has interrupts disabled. A hook must
+//! therefore never block: no `Mutex::lock`, no printing to the screen, no
+//! allocation. Atomics and `try_lock` are fine.
+
+use std3::__reexports::x86_64;
+use std3::mem;
+use std3::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// An NMI hook. Returns `true` if it recognised the NMI as its own.
+#[unstable(feature = "rinuxcore_nmi", issue = "none")]
+pub type NmiHook = fn(&InterruptStackFrame) -> bool;
+
+static HOOK: AtomicUsize = AtomicUsize::new(0);
+static COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Installs `hook`, replacing any previous one. Used for watchdogs or a
+/// "dump state" button wired to the NMI line.
+#[unstable(feature = "rinuxcore_nmi", issue = "none")]
+pub fn set_hook(hook: NmiHook) {
+    HOOK.store(hook as usize, Ordering::SeqCst);
+}
+
+/// Removes the installed hook.
+#[unstable(feature = "rinuxcore_nmi", issue = "none")]
+pub fn clear_hook() {
+    HOOK.store(0, Ordering::SeqCst);
+}
+
+/// Number of NMIs received since boot.
+#[unstable(feature = "rinuxcore_nmi", issue = "none")]
+pub fn count() -> u64 {
+    COUNT.load(Ordering::Relaxed)
+}
+
+/// Runs the hook. Returns `false` if nothing claimed the NMI and the chipset
+/// reports a hardware error, which the caller treats as fatal.
+pub(crate) fn handle(stack_frame: &InterruptStackFrame) -> bool {
+    COUNT.fetch_add(1, Ordering::Relaxed);
+    let hook = HOOK.load(Ordering::SeqCst);
+    if hook != 0 {
+        let hook = unsafe { mem::transmute::<usize, NmiHook>(hook) };
+        if hook(stack_frame) {
+            return true;
+        }
+    }
+    hardware_error().is_none()
+}
+
+/// Reads system control port B: bit 7 is a memory parity / SERR# error, bit
+/// 6 an I/O channel check.
+pub(crate) fn hardware_error() -> Option<u8> {
+    let status: u8 = unsafe { Port::new(0x61).read() };
+    if status & 0xC0 != 0 {
+        Some(status)
+    } else {
+        None
+    }
+}
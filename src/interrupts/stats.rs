@@ -0,0 +1,149 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Per-vector interrupt counters, updated by every handler in
+//! [`interrupts`](super).
+
+use super::{lapic, InterruptIndex, PIC_1_OFFSET};
+use crate::{println, serial_println};
+use std3::sync::atomic::{AtomicU64, Ordering};
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static COUNTS: [AtomicU64; 256] = [ZERO; 256];
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+const EXCEPTIONS: [&str; 32] = [
+    "divide error",
+    "debug",
+    "non-maskable interrupt",
+    "breakpoint",
+    "overflow",
+    "bound range exceeded",
+    "invalid opcode",
+    "device not available",
+    "double fault",
+    "coprocessor segment overrun",
+    "invalid TSS",
+    "segment not present",
+    "stack-segment fault",
+    "general protection fault",
+    "page fault",
+    "reserved",
+    "x87 floating-point",
+    "alignment check",
+    "machine check",
+    "SIMD floating-point",
+    "virtualization",
+    "control protection",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "hypervisor injection",
+    "VMM communication",
+    "security",
+    "reserved",
+];
+
+pub(crate) fn record(vector: u8) {
+    COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_spurious() {
+    SPURIOUS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of times `vector` has been delivered since boot.
+#[unstable(feature = "rinuxcore_interrupt_stats", issue = "none")]
+pub fn count(vector: u8) -> u64 {
+    COUNTS[usize::from(vector)].load(Ordering::Relaxed)
+}
+
+/// Number of spurious IRQ 7 / IRQ 15 interrupts. These are also included in
+/// the counts for their vectors.
+#[unstable(feature = "rinuxcore_interrupt_stats", issue = "none")]
+pub fn spurious() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/// Copies every counter. Not atomic as a whole, but each entry is.
+#[unstable(feature = "rinuxcore_interrupt_stats", issue = "none")]
+pub fn snapshot() -> [u64; 256] {
+    let mut counts = [0; 256];
+    for (count, counter) in counts.iter_mut().zip(COUNTS.iter()) {
+        *count = counter.load(Ordering::Relaxed);
+    }
+    counts
+}
+
+/// Human readable name of `vector`.
+#[unstable(feature = "rinuxcore_interrupt_stats", issue = "none")]
+pub fn name(vector: u8) -> &'static str {
+    const TIMER: u8 = InterruptIndex::Timer as u8;
+    const KEYBOARD: u8 = InterruptIndex::Keyboard as u8;
+    const SPURIOUS_MASTER: u8 = InterruptIndex::SpuriousMaster as u8;
+    const RTC: u8 = InterruptIndex::Rtc as u8;
+    const SPURIOUS_SLAVE: u8 = InterruptIndex::SpuriousSlave as u8;
+
+    match vector {
+        0..=31 => EXCEPTIONS[usize::from(vector)],
+        TIMER => "IRQ0 timer",
+        KEYBOARD => "IRQ1 keyboard",
+        SPURIOUS_MASTER => "IRQ7 spurious",
+        RTC => "IRQ8 rtc",
+        SPURIOUS_SLAVE => "IRQ15 spurious",
+        lapic::SPURIOUS_VECTOR => "APIC spurious",
+        lapic::WAKEUP_VECTOR => "IPI wakeup",
+        v if (PIC_1_OFFSET..PIC_1_OFFSET + 16).contains(&v) => "IRQ",
+        _ => "",
+    }
+}
+
+/// Prints a `/proc/interrupts` style table of every vector that has fired,
+/// to the screen and COM1.
+#[unstable(feature = "rinuxcore_interrupt_stats", issue = "none")]
+pub fn print() {
+    let counts = snapshot();
+    println!(" VEC       COUNT  NAME");
+    serial_println!(" VEC       COUNT  NAME");
+    for (vector, &count) in counts.iter().enumerate() {
+        if count == 0 {
+            continue;
+        }
+        let name = name(vector as u8);
+        println!("{:>4} {:>11}  {}", vector, count, name);
+        serial_println!("{:>4} {:>11}  {}", vector, count, name);
+    }
+    println!(" SPU {:>11}  spurious IRQs", spurious());
+    serial_println!(" SPU {:>11}  spurious IRQs", spurious());
+}
+
+#[test_case]
+fn test_breakpoint_is_counted() {
+    let before = count(3);
+    std3::__reexports::x86_64::instructions::interrupts::int3();
+    assert_eq!(count(3), before + 1);
+}
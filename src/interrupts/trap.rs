@@ -0,0 +1,127 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Assembly entry points that save every general purpose register.
+//!
+//! `extern "x86-interrupt"` handlers only see the interrupt stack frame. The
+//! few handlers that need to inspect or change the full register state, like
+//! the GDB stub and the timer that preempts user programs, are entered through
+//! a stub generated by [`trap_entry!`] instead, which hands them a
+//! `&mut TrapFrame`. The stub also does the `swapgs` for interrupted user
+//! code, see [`per_cpu`](crate::per_cpu).
+
+/// Register state saved on interrupt entry, in stack order.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct TrapFrame {
+    pub(crate) r15: u64,
+    pub(crate) r14: u64,
+    pub(crate) r13: u64,
+    pub(crate) r12: u64,
+    pub(crate) r11: u64,
+    pub(crate) r10: u64,
+    pub(crate) r9: u64,
+    pub(crate) r8: u64,
+    pub(crate) rbp: u64,
+    pub(crate) rdi: u64,
+    pub(crate) rsi: u64,
+    pub(crate) rdx: u64,
+    pub(crate) rcx: u64,
+    pub(crate) rbx: u64,
+    pub(crate) rax: u64,
+    /// The CPU's error code, or zero for vectors that do not push one.
+    pub(crate) error_code: u64,
+    pub(crate) rip: u64,
+    pub(crate) cs: u64,
+    pub(crate) rflags: u64,
+    pub(crate) rsp: u64,
+    pub(crate) ss: u64,
+}
+
+/// Defines an interrupt entry stub `$entry` that builds a [`TrapFrame`] and
+/// calls `$handler`, which must be `#[no_mangle] extern "C" fn(&mut TrapFrame)`.
+///
+/// Only for vectors without a CPU error code; the stub pushes a zero in its
+/// place. The stub's address goes into the IDT through `set_handler_addr`.
+macro_rules! trap_entry {
+    ($entry:ident => $handler:ident) => {
+        std3::arch::global_asm!(concat!(
+            ".global ", stringify!($entry), "\n",
+            stringify!($entry), ":\n",
+            "push 0\n",
+            // Switch to the kernel's GS base if ring 3 was interrupted.
+            "test qword ptr [rsp + 16], 3\n",
+            "jz 1f\n",
+            "swapgs\n",
+            "1:\n",
+            "push rax\n",
+            "push rbx\n",
+            "push rcx\n",
+            "push rdx\n",
+            "push rsi\n",
+            "push rdi\n",
+            "push rbp\n",
+            "push r8\n",
+            "push r9\n",
+            "push r10\n",
+            "push r11\n",
+            "push r12\n",
+            "push r13\n",
+            "push r14\n",
+            "push r15\n",
+            "mov rdi, rsp\n",
+            "cld\n",
+            // The CPU aligned the stack before pushing its frame; 21 pushes
+            // later it is 8 bytes off.
+            "sub rsp, 8\n",
+            "call ", stringify!($handler), "\n",
+            "add rsp, 8\n",
+            "pop r15\n",
+            "pop r14\n",
+            "pop r13\n",
+            "pop r12\n",
+            "pop r11\n",
+            "pop r10\n",
+            "pop r9\n",
+            "pop r8\n",
+            "pop rbp\n",
+            "pop rdi\n",
+            "pop rsi\n",
+            "pop rdx\n",
+            "pop rcx\n",
+            "pop rbx\n",
+            "pop rax\n",
+            "add rsp, 8\n",
+            "test qword ptr [rsp + 8], 3\n",
+            "jz 2f\n",
+            "swapgs\n",
+            "2:\n",
+            "iretq\n",
+        ));
+
+        extern "C" {
+            fn $entry();
+        }
+    };
+}
@@ -65,6 +65,9 @@ fn panic(info: &std3::panic::PanicInfo) -> ! {
 }
 ```
 
+Enabling the `panic_handler` feature installs a default panic handler that
+prints a full crash report, so the handler above can be left out.
+
 [STD3 Docs Here](https://www.github.linkrbot.com/std3)
 */
 
@@ -107,6 +110,8 @@ pub use std3::__bootloader::bootloader::BootInfo;
 #[stable(feature = "rinuxcore", since = "0.1.23")]
 use std3::panic::PanicInfo;
 #[stable(feature = "rinuxcore", since = "0.1.23")]
+use std3::sync::atomic::{AtomicBool, Ordering};
+#[stable(feature = "rinuxcore", since = "0.1.23")]
 use memory::BootInfoFrameAllocator;
 #[unstable(feature = "rinuxcore_custom_config", issue = "none")]
 pub mod conf;
@@ -117,6 +122,7 @@ extern crate alloc;
 pub mod allocator;
 #[stable(feature = "rinuxcore", since = "0.1.23")]
 #[doc(hidden)]
+#[macro_use]
 pub mod interrupts;
 #[stable(feature = "rinuxcore", since = "0.1.23")]
 #[doc(hidden)]
@@ -131,6 +137,25 @@ use std3::__reexports::x86_64;
 pub mod gdt;
 #[unstable(feature = "rinuxcore_task", issue = "none")]
 pub mod task;
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+pub mod time;
+#[unstable(feature = "rinuxcore_thread", issue = "none")]
+pub mod thread;
+#[unstable(feature = "rinuxcore_smp", issue = "none")]
+pub mod smp;
+#[unstable(feature = "rinuxcore_per_cpu", issue = "none")]
+pub mod per_cpu;
+#[unstable(feature = "rinuxcore_acpi", issue = "none")]
+pub(crate) mod acpi;
+#[unstable(feature = "rinuxcore_backtrace", issue = "none")]
+pub mod backtrace;
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub mod userspace;
+#[unstable(feature = "rinuxcore_panic", issue = "none")]
+pub mod panic;
+#[unstable(feature = "rinuxcore_gdb", issue = "none")]
+#[cfg(feature = "gdb")]
+pub mod gdb;
 
 #[unstable(feature = "rinuxcore_enderpearl", issue = "none")]
 #[cfg(feature = "epearl")]
@@ -258,8 +283,17 @@ pub fn init(boot_info: &'static BootInfo) {
         }
 
         use x86_64::VirtAddr;
+        per_cpu::init(0);
         gdt::init();
+        userspace::syscall::init();
         interrupts::init_idt();
+        if let Some(banks) = interrupts::mce::init() {
+            if CONFIG.quiet_boot != true {
+                print_ok!("[OK] Machine check enabled ({} banks)\n", banks);
+            };
+        }
+        #[cfg(feature = "gdb")]
+        gdb::init();
         interrupts::PICS.lock().initialize();
         if CONFIG.quiet_boot != true {
             print_ok!("[OK] Interupts initialized\n");
@@ -288,6 +322,12 @@ pub fn init(boot_info: &'static BootInfo) {
                 print_err!("[ERR] Heap Initialization\n");
             }
         };
+        memory::install(mapper, frame_allocator);
+        task::deferred::init();
+
+        time::init();
+        thread::init();
+        smp::init();
     }
 
     #[cfg(test)]
@@ -341,9 +381,19 @@ where
     }
 }
 
+/// Set by [`test_runner`], so the panic handler can end a test run in a
+/// kernel that uses it, where rinuxcore itself is not built with `cfg(test)`
+static RUNNING_TESTS: AtomicBool = AtomicBool::new(false);
+
+#[cfg(any(test, feature = "panic_handler", feature = "gdb"))]
+pub(crate) fn running_tests() -> bool {
+    RUNNING_TESTS.load(Ordering::Relaxed)
+}
+
 /// Runs Tests
 #[stable(feature = "rinuxcore", since = "0.1.23")]
 pub fn test_runner(tests: &[&dyn Testable]) {
+    RUNNING_TESTS.store(true, Ordering::Relaxed);
     serial_println!("Running {} tests", tests.len());
     for test in tests {
         test.run();
@@ -24,14 +24,30 @@
 
 use crate::vga_buffer::print_ok;
 use std3::{__bootloader::bootloader,__reexports::x86_64};
+use std3::sync::{atomic::{AtomicU64, Ordering}, Mutex};
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 #[unstable(feature = "rinuxcore_x86_64", issue = "none")]
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable,
+        PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
     PhysAddr, VirtAddr,
 };
 
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Start of the region `alloc_stack` maps kernel stacks into.
+const KERNEL_STACKS_START: u64 = 0x_5555_0000_0000;
+static NEXT_STACK: AtomicU64 = AtomicU64::new(KERNEL_STACKS_START);
+
+/// The kernel's page table mapper, available once `crate::init` has set up the heap.
+pub(crate) static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+/// The frame allocator used for every mapping made after boot.
+pub(crate) static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
 pub(crate) unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::SeqCst);
     let level_4_table = active_level_4_table(physical_memory_offset);
     if !crate::CONFIG.quiet_boot {
         print_ok!("[OK] RAM initialized\n");
@@ -51,6 +67,157 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr
 }
 
+/// Hands the boot-time mapper and frame allocator over to the kernel-wide statics.
+pub(crate) fn install(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    *MAPPER.lock() = Some(mapper);
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// Start of the bootloader's complete physical memory mapping.
+pub(crate) fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst))
+}
+
+/// Translates a physical address into the bootloader's complete physical memory mapping.
+pub(crate) fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst) + addr.as_u64())
+}
+
+/// Makes sure a device's MMIO registers are reachable through `phys_to_virt`.
+///
+/// The bootloader only maps physical memory that shows up in the memory map,
+/// so register blocks such as the HPET or local APIC may be missing. Pages
+/// that are already mapped are left alone, missing ones are mapped uncached.
+pub(crate) fn map_mmio(addr: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let virt = phys_to_virt(addr);
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return Err(MapToError::FrameAllocationFailed),
+    };
+
+    let first = PhysFrame::<Size4KiB>::containing_address(addr);
+    let last = PhysFrame::<Size4KiB>::containing_address(addr + size.max(1) - 1u64);
+    for frame in PhysFrame::range_inclusive(first, last) {
+        let page = Page::containing_address(phys_to_virt(frame.start_address()));
+        if mapper.translate_addr(page.start_address()).is_some() {
+            continue;
+        }
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+    Ok(virt)
+}
+
+/// Maps a fresh stack of at least `size` bytes and returns its top.
+///
+/// Stacks never get unmapped; each one sits above an unmapped guard page so
+/// an overflow faults instead of corrupting its neighbour.
+pub(crate) fn alloc_stack(size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let pages = (size + 4095) / 4096;
+    let guard = NEXT_STACK.fetch_add((pages + 1) * 4096, Ordering::Relaxed);
+    let start = Page::<Size4KiB>::containing_address(VirtAddr::new(guard + 4096));
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return Err(MapToError::FrameAllocationFailed),
+    };
+
+    for page in Page::range(start, start + pages) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+    Ok((start + pages).start_address())
+}
+
+/// Maps `frame` at the virtual address equal to its physical one, for code
+/// that runs while paging is being switched on. Returns whether a mapping
+/// was added, in which case `unmap_identity` should remove it again.
+pub(crate) fn identity_map(frame: PhysFrame) -> Result<bool, MapToError<Size4KiB>> {
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return Err(MapToError::FrameAllocationFailed),
+    };
+
+    let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    match mapper.translate_addr(page.start_address()) {
+        Some(addr) if addr == frame.start_address() => return Ok(false),
+        Some(_) => return Err(MapToError::PageAlreadyMapped(frame)),
+        None => {}
+    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    Ok(true)
+}
+
+/// Removes a mapping added by `identity_map`.
+pub(crate) fn unmap_identity(frame: PhysFrame) {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    if let Some(mapper) = MAPPER.lock().as_mut() {
+        if let Ok((_, flush)) = mapper.unmap(page) {
+            flush.flush();
+        }
+    }
+}
+
+/// Type of the memory map region containing `addr`, if any.
+pub(crate) fn region_type(addr: PhysAddr) -> Option<MemoryRegionType> {
+    let frame_allocator = FRAME_ALLOCATOR.lock();
+    frame_allocator
+        .as_ref()?
+        .memory_map
+        .iter()
+        .find(|r| (r.range.start_addr()..r.range.end_addr()).contains(&addr.as_u64()))
+        .map(|r| r.region_type)
+}
+
+/// Checks whether `addr` is mapped in the active page table.
+///
+/// Walks the tables by hand instead of going through `MAPPER`, so it can be
+/// used from exception handlers that may have interrupted a lock holder.
+pub(crate) fn is_mapped(addr: VirtAddr) -> bool {
+    page_flags(addr).is_some()
+}
+
+/// Effective flags of the page containing `addr`, or `None` if it is not
+/// mapped. `WRITABLE` and `USER_ACCESSIBLE` are only reported if every level
+/// of the walk allows them, as the CPU requires. Lock-free like `is_mapped`.
+pub(crate) fn page_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+    let mut table = level_4_table_frame.start_address();
+    let mut inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    for (level, index) in indices.iter().enumerate() {
+        let entries = unsafe { &*phys_to_virt(table).as_ptr::<PageTable>() };
+        let entry = &entries[*index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        inherited &= flags;
+        // 1 GiB and 2 MiB pages end the walk early.
+        let huge = (level == 1 || level == 2) && flags.contains(PageTableFlags::HUGE_PAGE);
+        if level == 3 || huge {
+            let restricted = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+            return Some((flags - restricted) | inherited);
+        }
+        table = entry.addr();
+    }
+    None
+}
+
 pub(crate) struct EmptyFrameAllocator;
 
 unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
@@ -62,6 +229,9 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
 pub(crate) struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    /// Head of the list of returned frames; each one holds the physical
+    /// address of the next in its first eight bytes, 0 ending the list.
+    free: u64,
 }
 
 impl BootInfoFrameAllocator {
@@ -72,6 +242,7 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free: 0,
         }
     }
 
@@ -80,14 +251,28 @@ impl BootInfoFrameAllocator {
         let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
         let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+        // The first MiB stays free for real-mode code such as the SMP trampoline.
+        let frame_addresses = frame_addresses.filter(|addr| *addr >= 0x10_0000);
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free != 0 {
+            let frame = PhysFrame::containing_address(PhysAddr::new(self.free));
+            self.free = unsafe { phys_to_virt(frame.start_address()).as_ptr::<u64>().read() };
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        phys_to_virt(frame.start_address()).as_mut_ptr::<u64>().write(self.free);
+        self.free = frame.start_address().as_u64();
+    }
+}
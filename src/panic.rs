@@ -0,0 +1,305 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Crash reporting, and the default panic handler behind the `panic_handler`
+//! feature.
+
+use crate::{print_err, serial_print, serial_println};
+use std3::__reexports::x86_64;
+use std3::arch::asm;
+use std3::panic::PanicInfo;
+use std3::fmt::{self, Write};
+use std3::sync::atomic::{AtomicBool, Ordering};
+use vga_buffer::CrashScreen;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::rflags;
+use x86_64::structures::idt::InterruptStackFrame;
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+macro_rules! report {
+    ($($arg:tt)*) => {{
+        print_err!($($arg)*);
+        serial_print!($($arg)*);
+    }};
+}
+
+/// General-purpose registers and flags of the running code.
+#[unstable(feature = "rinuxcore_panic", issue = "none")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    /// `rax`
+    pub rax: u64,
+    /// `rbx`
+    pub rbx: u64,
+    /// `rcx`
+    pub rcx: u64,
+    /// `rdx`
+    pub rdx: u64,
+    /// `rsi`
+    pub rsi: u64,
+    /// `rdi`
+    pub rdi: u64,
+    /// `rbp`
+    pub rbp: u64,
+    /// `rsp`
+    pub rsp: u64,
+    /// `r8`
+    pub r8: u64,
+    /// `r9`
+    pub r9: u64,
+    /// `r10`
+    pub r10: u64,
+    /// `r11`
+    pub r11: u64,
+    /// `r12`
+    pub r12: u64,
+    /// `r13`
+    pub r13: u64,
+    /// `r14`
+    pub r14: u64,
+    /// `r15`
+    pub r15: u64,
+    /// `rflags`
+    pub rflags: u64,
+}
+
+impl Registers {
+    /// Reads the registers as they are where this is inlined. Call it first
+    /// thing in a panic handler: only what the handler's prologue moved
+    /// around differs from the code that panicked.
+    #[unstable(feature = "rinuxcore_panic", issue = "none")]
+    #[inline(always)]
+    pub fn capture() -> Registers {
+        let (rax, rcx, rdx, rsi, rdi): (u64, u64, u64, u64, u64);
+        let (r8, r9, r10, r11): (u64, u64, u64, u64);
+        let (r12, r13, r14, r15): (u64, u64, u64, u64);
+        let (rbx, rbp, rsp): (u64, u64, u64);
+        unsafe {
+            // Outputs only: each is read from its register as the block
+            // ends, which is where it was on the way in.
+            asm!(
+                "",
+                out("rax") rax,
+                out("rcx") rcx,
+                out("rdx") rdx,
+                out("rsi") rsi,
+                out("rdi") rdi,
+                out("r8") r8,
+                out("r9") r9,
+                out("r10") r10,
+                out("r11") r11,
+                out("r12") r12,
+                out("r13") r13,
+                out("r14") r14,
+                out("r15") r15,
+                options(nomem, nostack, preserves_flags)
+            );
+            // LLVM keeps these three for itself, so they are copied out.
+            asm!("mov {}, rbx", out(reg) rbx, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+        }
+        Registers {
+            rax,
+            rbx,
+            rcx,
+            rdx,
+            rsi,
+            rdi,
+            rbp,
+            rsp,
+            r8,
+            r9,
+            r10,
+            r11,
+            r12,
+            r13,
+            r14,
+            r15,
+            rflags: rflags::read_raw(),
+        }
+    }
+}
+
+/// Writes a crash report for `info` to COM1 and replaces the screen with a
+/// crash screen: the message and location, the current task, the registers
+/// at `report`'s entry, the control registers, heap statistics and a
+/// backtrace.
+///
+/// Disables interrupts and takes over the console locks, so it never
+/// deadlocks on a writer the panicking code was holding.
+#[unstable(feature = "rinuxcore_panic", issue = "none")]
+#[inline(never)]
+pub fn report(info: &PanicInfo) {
+    let registers = Registers::capture();
+    report_with(info, &registers);
+}
+
+/// Like [`report`], with `registers` captured by the caller, normally at the
+/// entry of its panic handler.
+#[unstable(feature = "rinuxcore_panic", issue = "none")]
+pub fn report_with(info: &PanicInfo, registers: &Registers) {
+    x86_64::instructions::interrupts::disable();
+    take_console();
+
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        serial_println!("[FAIL] panicked while reporting a panic: {}", info);
+        return;
+    }
+
+    // A double fault may have interrupted a `swapgs` pair
+    let task = if crate::per_cpu::kernel_gs_active() {
+        crate::task::current_task_id()
+    } else {
+        None
+    };
+    // A fault handler that panics has already drawn its own screen.
+    if !vga_buffer::is_crashed() {
+        let mut frames = Frames::new();
+        crate::backtrace::walk(|address| frames.push(address));
+        let mut screen = crash_screen("KERNEL PANIC");
+        screen.field("Message", format_args!("{}", info));
+        match task {
+            Some(id) => screen.field("Task", format_args!("{}", id)),
+            None => screen.field("Task", format_args!("<none>")),
+        }
+        frames.draw(&mut screen);
+    }
+
+    report!("[FAIL] KERNEL PANIC\n");
+    report!("{}\n", info);
+    match task {
+        Some(id) => report!("Task: {}\n", id),
+        None => report!("Task: <none>\n"),
+    }
+
+    let r = registers;
+    report!("Registers:\n");
+    report!("  rax: {:#018x}  rbx: {:#018x}  rcx: {:#018x}\n", r.rax, r.rbx, r.rcx);
+    report!("  rdx: {:#018x}  rsi: {:#018x}  rdi: {:#018x}\n", r.rdx, r.rsi, r.rdi);
+    report!("  rbp: {:#018x}  rsp: {:#018x}  r8:  {:#018x}\n", r.rbp, r.rsp, r.r8);
+    report!("  r9:  {:#018x}  r10: {:#018x}  r11: {:#018x}\n", r.r9, r.r10, r.r11);
+    report!("  r12: {:#018x}  r13: {:#018x}  r14: {:#018x}\n", r.r12, r.r13, r.r14);
+    report!("  r15: {:#018x}  rflags: {:#018x}\n", r.r15, r.rflags);
+    report!("  cr0: {:#018x}  cr2: {:#018x}\n", Cr0::read_raw(), Cr2::read().as_u64());
+    report!(
+        "  cr3: {:#018x}  cr4: {:#018x}\n",
+        Cr3::read().0.start_address().as_u64(),
+        Cr4::read_raw()
+    );
+
+    let heap = crate::allocator::stats();
+    report!("Heap:\n");
+    report!(
+        "  in use: {} / {} bytes (peak {})\n",
+        heap.bytes_in_use, heap.heap_size, heap.peak_bytes_in_use
+    );
+    report!(
+        "  allocations: {}  deallocations: {}  failed: {}\n",
+        heap.allocations, heap.deallocations, heap.failed_allocations
+    );
+
+    crate::backtrace::print();
+}
+
+/// Replaces the screen with a crash screen for a fatal CPU exception. Must be
+/// called directly from the exception handler.
+#[inline(never)]
+pub(crate) fn fault_screen(
+    kind: &str,
+    stack_frame: &InterruptStackFrame,
+    cr2: Option<u64>,
+    detail: fmt::Arguments,
+) {
+    let mut frames = Frames::new();
+    crate::backtrace::walk_fault(stack_frame, |address| frames.push(address));
+    let mut screen = crash_screen(kind);
+    screen.field("Error", detail);
+    screen.field("RIP", format_args!("{:#018x}", stack_frame.instruction_pointer.as_u64()));
+    if let Some(cr2) = cr2 {
+        screen.field("CR2", format_args!("{:#018x}", cr2));
+    }
+    frames.draw(&mut screen);
+}
+
+/// Stops the other CPUs, then takes over the screen and serial locks
+/// whoever holds them.
+pub(crate) fn take_console() {
+    crate::smp::stop_other_cpus();
+    unsafe {
+        vga_buffer::force_unlock();
+        crate::serial::force_unlock();
+    }
+}
+
+/// Every caller is on a crash path, with interrupts disabled and the other
+/// CPUs stopped, and prints only through the screen from here on.
+fn crash_screen(kind: &str) -> CrashScreen {
+    crate::smp::stop_other_cpus();
+    unsafe { CrashScreen::new(kind, crate::VERSION) }
+}
+
+/// Return addresses collected before the screen is drawn, enough to fill it.
+struct Frames {
+    addresses: [u64; 16],
+    len: usize,
+}
+
+impl Frames {
+    fn new() -> Frames {
+        Frames {
+            addresses: [0; 16],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, address: u64) {
+        if self.len < self.addresses.len() {
+            self.addresses[self.len] = address;
+            self.len += 1;
+        }
+    }
+
+    fn draw(&self, screen: &mut CrashScreen) {
+        let _ = writeln!(screen, "Backtrace:");
+        for (index, &address) in self.addresses[..self.len].iter().enumerate() {
+            let _ = match crate::backtrace::symbolize(address) {
+                Some(symbol) => writeln!(screen, "  #{:<2} {:#018x} {}", index, address, symbol),
+                None => writeln!(screen, "  #{:<2} {:#018x}", index, address),
+            };
+        }
+    }
+}
+
+#[cfg(any(test, feature = "panic_handler"))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let registers = Registers::capture();
+    report_with(info, &registers);
+    if cfg!(test) || crate::running_tests() {
+        crate::exit_qemu(crate::QemuExitCode::Failed);
+    }
+    crate::hlt_loop();
+}
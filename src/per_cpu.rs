@@ -0,0 +1,260 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Per-CPU data.
+//!
+//! Each CPU's `GS_BASE` points at its entry in a table whose first word is
+//! the CPU's index, so finding the running CPU is one `gs` relative load.
+//! Statics declared with [`per_cpu!`] keep a slot per CPU and hand out the
+//! running CPU's:
+//!
+//! ```rust
+//! rinuxcore::per_cpu! {
+//!     static POLLS: rinuxcore::per_cpu::Counter = rinuxcore::per_cpu::Counter::new();
+//! }
+//!
+//! POLLS.get().increment();
+//! ```
+//!
+//! [`cpu_id`] names the running CPU and
+//! [`task::current_task_id`](crate::task::current_task_id) the task it is
+//! polling.
+//!
+//! While a CPU runs user code, `swapgs` parks the kernel's base in
+//! `KERNEL_GS_BASE`. Every way back into the kernel swaps it again before
+//! touching per-CPU data: the syscall and `trap_entry!` stubs in assembly,
+//! `x86-interrupt` handlers through [`KernelGs`]. The NMI, machine check and
+//! double fault handlers can arrive halfway through such a switch and must
+//! not use per-CPU data, or check [`kernel_gs_active`] first.
+
+use crate::smp::{self, MAX_CPUS};
+use crate::userspace;
+use std3::__reexports::x86_64;
+use std3::arch::asm;
+use std3::fmt;
+use std3::mem;
+use std3::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::segmentation::{Segment64, GS};
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+/// What `GS_BASE` points at, one per CPU. The syscall entry stub addresses
+/// the fields by offset, so their order is fixed.
+#[repr(C)]
+struct CpuBlock {
+    /// `gs:[0]`
+    cpu: usize,
+    /// `gs:[8]`, top of the stack `SYSCALL` switches to
+    syscall_stack: AtomicU64,
+    /// `gs:[16]`, the user stack pointer while a system call runs
+    syscall_user_rsp: AtomicU64,
+}
+
+const fn blocks() -> [CpuBlock; MAX_CPUS] {
+    const EMPTY: CpuBlock = CpuBlock {
+        cpu: 0,
+        syscall_stack: AtomicU64::new(0),
+        syscall_user_rsp: AtomicU64::new(0),
+    };
+    let mut blocks = [EMPTY; MAX_CPUS];
+    let mut cpu = 0;
+    while cpu < MAX_CPUS {
+        blocks[cpu].cpu = cpu;
+        cpu += 1;
+    }
+    blocks
+}
+
+static BLOCKS: [CpuBlock; MAX_CPUS] = blocks();
+/// Guards `cpu_id` against running before the bootstrap processor's `GS_BASE` is set.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Points the running CPU's `GS_BASE` at its block. Called by every CPU
+/// before anything else that might use per-CPU data.
+pub(crate) fn init(cpu: usize) {
+    unsafe {
+        GS::write_base(VirtAddr::from_ptr(&BLOCKS[cpu]));
+        KernelGsBase::write(VirtAddr::zero());
+    }
+    INITIALIZED.store(true, Ordering::Release);
+}
+
+/// Sets the stack the running CPU's system calls run on.
+pub(crate) fn set_syscall_stack(top: VirtAddr) {
+    BLOCKS[cpu_id()].syscall_stack.store(top.as_u64(), Ordering::Relaxed);
+}
+
+/// Whether `GS_BASE` holds the kernel's base, so per-CPU data can be used.
+/// For fault paths that may have interrupted a `swapgs` pair.
+pub(crate) fn kernel_gs_active() -> bool {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return true;
+    }
+    let base = GsBase::read().as_u64();
+    let start = BLOCKS.as_ptr() as u64;
+    (start..start + mem::size_of_val(&BLOCKS) as u64).contains(&base)
+}
+
+/// Index of the running CPU, 0 being the bootstrap processor.
+#[unstable(feature = "rinuxcore_per_cpu", issue = "none")]
+pub fn cpu_id() -> usize {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return 0;
+    }
+    let cpu: usize;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) cpu, options(nostack, preserves_flags, readonly)) };
+    cpu
+}
+
+/// A value with one instance per CPU, see [`per_cpu!`].
+#[unstable(feature = "rinuxcore_per_cpu", issue = "none")]
+pub struct PerCpu<T> {
+    slots: [T; MAX_CPUS],
+}
+
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    #[unstable(feature = "rinuxcore_per_cpu", issue = "none")]
+    pub const fn new(slots: [T; MAX_CPUS]) -> PerCpu<T> {
+        PerCpu { slots }
+    }
+
+    /// The running CPU's instance.
+    #[unstable(feature = "rinuxcore_per_cpu", issue = "none")]
+    pub fn get(&self) -> &T {
+        &self.slots[cpu_id()]
+    }
+
+    /// Another CPU's instance, if `cpu` is in range.
+    #[unstable(feature = "rinuxcore_per_cpu", issue = "none")]
+    pub fn get_for(&self, cpu: usize) -> Option<&T> {
+        self.slots.get(cpu)
+    }
+
+    /// Every CPU's instance, including CPUs that are not online.
+    #[unstable(feature = "rinuxcore_per_cpu", issue = "none")]
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.slots.iter()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for PerCpu<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.slots.iter().take(smp::cpu_count())).finish()
+    }
+}
+
+/// Declares statics with one instance per CPU, of type [`PerCpu<T>`].
+///
+/// The initializer must be a constant expression; it is evaluated once per
+/// CPU.
+#[unstable(feature = "rinuxcore_per_cpu", issue = "none")]
+#[macro_export]
+macro_rules! per_cpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::per_cpu::PerCpu<$ty> = {
+                const INIT: $ty = $init;
+                $crate::per_cpu::PerCpu::new([INIT; $crate::smp::MAX_CPUS])
+            };
+        )*
+    };
+}
+
+/// An event counter kept per CPU, so counting never bounces a cache line
+/// between CPUs.
+#[unstable(feature = "rinuxcore_per_cpu", issue = "none")]
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    /// A counter at zero.
+    #[unstable(feature = "rinuxcore_per_cpu", issue = "none")]
+    pub const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    /// Counts one event.
+    #[unstable(feature = "rinuxcore_per_cpu", issue = "none")]
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The count so far.
+    #[unstable(feature = "rinuxcore_per_cpu", issue = "none")]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl PerCpu<Counter> {
+    /// Sum over every CPU.
+    #[unstable(feature = "rinuxcore_per_cpu", issue = "none")]
+    pub fn total(&self) -> u64 {
+        self.iter().map(Counter::get).sum()
+    }
+}
+
+/// Switches to the kernel's `GS_BASE` for the lifetime of an
+/// `x86-interrupt` handler that interrupted ring 3, or a faulting `iretq`
+/// into ring 3, and back when dropped.
+/// Handlers that leave through `userspace::return_to_kernel` never drop it,
+/// which is right: they stay in the kernel.
+pub(crate) struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    /// Must be the first thing the handler does.
+    pub(crate) fn enter(stack_frame: &InterruptStackFrame) -> KernelGs {
+        let swapped = userspace::from_user(stack_frame) || userspace::entering_user(stack_frame);
+        if swapped {
+            unsafe { GS::swap() };
+        }
+        KernelGs { swapped }
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { GS::swap() };
+        }
+    }
+}
+
+#[test_case]
+fn test_per_cpu_counter() {
+    per_cpu! {
+        static EVENTS: Counter = Counter::new();
+    }
+
+    EVENTS.get().increment();
+    EVENTS.get().increment();
+    assert_eq!(cpu_id(), 0);
+    assert_eq!(EVENTS.get().get(), 2);
+    assert_eq!(EVENTS.total(), 2);
+}
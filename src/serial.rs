@@ -34,6 +34,18 @@ lazy_static! {
         serial_port.init();
         Mutex::new(serial_port)
     };
+    #[stable(feature = "rinuxcore", since = "0.1.23")]
+    pub(crate) static ref SERIAL2: Mutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(0x2F8) };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+}
+
+/// Releases the serial port locks, even if they are held. Crash paths only.
+pub(crate) unsafe fn force_unlock() {
+    SERIAL1.force_unlock();
+    SERIAL2.force_unlock();
 }
 
 #[stable(feature = "rinuxcore", since = "0.1.23")]
@@ -0,0 +1,76 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! The parts of the MADT ("APIC" table) needed to find the other CPUs.
+
+use crate::acpi;
+use std3::__reexports::x86_64::PhysAddr;
+use std3::{convert::TryInto, mem, vec::Vec};
+
+const PROCESSOR_LOCAL_APIC: u8 = 0;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const PROCESSOR_ENABLED: u32 = 1;
+
+pub(super) struct Madt {
+    /// Physical address of every CPU's local APIC registers.
+    pub(super) local_apic_address: PhysAddr,
+    /// APIC IDs of the usable processors, the bootstrap processor included.
+    pub(super) apic_ids: Vec<u8>,
+}
+
+pub(super) fn parse() -> Option<Madt> {
+    let bytes = acpi::find_table(b"APIC")?.bytes();
+    let header = mem::size_of::<acpi::SdtHeader>();
+    let address = bytes.get(header..header + 4)?;
+    let mut local_apic_address = u32::from_le_bytes(address.try_into().ok()?) as u64;
+    let mut apic_ids = Vec::new();
+
+    // Fixed fields are the local APIC address and flags, then variable length
+    // entries of type, length and contents.
+    let mut offset = header + 8;
+    while let Some(&[kind, length]) = bytes.get(offset..offset + 2) {
+        let entry = match bytes.get(offset..offset + length as usize) {
+            Some(entry) if length >= 2 => entry,
+            _ => break,
+        };
+        match kind {
+            PROCESSOR_LOCAL_APIC if entry.len() >= 8 => {
+                let flags = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+                if flags & PROCESSOR_ENABLED != 0 {
+                    apic_ids.push(entry[3]);
+                }
+            }
+            LOCAL_APIC_ADDRESS_OVERRIDE if entry.len() >= 12 => {
+                local_apic_address = u64::from_le_bytes(entry[4..12].try_into().unwrap());
+            }
+            _ => {}
+        }
+        offset += entry.len();
+    }
+
+    Some(Madt {
+        local_apic_address: PhysAddr::new(local_apic_address),
+        apic_ids,
+    })
+}
@@ -0,0 +1,256 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Multiprocessor bring-up.
+//!
+//! The bootstrap processor finds the others in the MADT and starts them one
+//! at a time with INIT-SIPI-SIPI through the real-mode trampoline. Each
+//! application processor loads its own GDT, TSS and interrupt stacks, the
+//! shared IDT, sets up `SYSCALL` with a stack of its own, and halts until it
+//! is handed code to run, such as the
+//! [multi-core executor](crate::task::multicore). Device interrupts keep
+//! going to the bootstrap processor only.
+//!
+//! Run QEMU with `-smp 4` to bring up three application processors.
+
+mod madt;
+mod trampoline;
+
+use crate::interrupts::{self, lapic};
+use crate::time::{spin_wait, Instant};
+use crate::userspace::syscall;
+use crate::vga_buffer::print_ok;
+use crate::{gdt, hlt_loop, memory, per_cpu, print_err};
+use conquer_once::spin::OnceCell;
+use std3::__reexports::x86_64;
+use std3::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std3::{mem, time::Duration, vec, vec::Vec};
+use x86_64::registers::control::Cr3;
+
+/// Most CPUs the kernel brings up, the bootstrap processor included.
+#[unstable(feature = "rinuxcore_smp", issue = "none")]
+pub const MAX_CPUS: usize = 16;
+
+const AP_STACK_SIZE: u64 = 16 * 1024;
+const START_TIMEOUT: Duration = Duration::from_millis(100);
+const STOP_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// APIC IDs by CPU index; index 0 is the bootstrap processor.
+static APIC_IDS: OnceCell<Vec<u8>> = OnceCell::uninit();
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+/// Set by the application processor being started once it is up.
+static STARTED: AtomicBool = AtomicBool::new(false);
+/// APIC ID of the CPU halting the others for a crash report, `usize::MAX`
+/// while none is. Crash paths may run on a user GS base, so they go by APIC
+/// ID rather than `per_cpu::cpu_id`.
+static STOPPER: AtomicUsize = AtomicUsize::new(usize::MAX);
+/// CPUs halted by `stop_other_cpus`.
+static STOPPED: AtomicUsize = AtomicUsize::new(0);
+/// What parked application processors run, as a `fn() -> !`; zero until
+/// `launch_aps`.
+static AP_ENTRY: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of CPUs found, whether or not they started.
+#[unstable(feature = "rinuxcore_smp", issue = "none")]
+pub fn cpu_count() -> usize {
+    APIC_IDS.try_get().map_or(1, |ids| ids.len())
+}
+
+/// Number of CPUs running kernel code.
+#[unstable(feature = "rinuxcore_smp", issue = "none")]
+pub fn online_cpus() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Index of the running CPU, 0 being the bootstrap processor.
+#[unstable(feature = "rinuxcore_smp", issue = "none")]
+pub fn current_cpu() -> usize {
+    per_cpu::cpu_id()
+}
+
+/// Has every application processor leave its parking loop and run `entry`.
+/// Only the first call has an effect.
+pub(crate) fn launch_aps(entry: fn() -> !) {
+    if AP_ENTRY.compare_exchange(0, entry as usize, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+        (1..cpu_count()).for_each(wake);
+    }
+}
+
+/// Halts every other online CPU with an NMI and waits briefly for them, so
+/// a crash path can take over the console locks. Only the first CPU to get
+/// here returns; any other one halts as well.
+pub(crate) fn stop_other_cpus() {
+    let cpu = lapic::id() as usize;
+    match STOPPER.compare_exchange(usize::MAX, cpu, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) if online_cpus() > 1 => {}
+        Ok(_) => return,
+        Err(stopper) if stopper == cpu => return,
+        Err(_) => halt(),
+    }
+    lapic::send_nmi_to_others();
+    let deadline = Instant::now().as_nanos() + STOP_TIMEOUT.as_nanos() as u64;
+    while STOPPED.load(Ordering::Acquire) + 1 < online_cpus() {
+        if Instant::now().as_nanos() >= deadline {
+            break;
+        }
+        std3::hint::spin_loop();
+    }
+}
+
+/// Called on every NMI: halts this CPU for good if another one is
+/// reporting a crash.
+pub(crate) fn stop_requested() {
+    let stopper = STOPPER.load(Ordering::Acquire);
+    if stopper != usize::MAX && stopper != lapic::id() as usize {
+        STOPPED.fetch_add(1, Ordering::AcqRel);
+        halt();
+    }
+}
+
+/// Interrupts stay off and, inside an NMI handler, further NMIs are held
+/// back until an `iretq` that never comes.
+fn halt() -> ! {
+    x86_64::instructions::interrupts::disable();
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Sends a wakeup IPI to `cpu`, ending its `hlt`.
+pub(crate) fn wake(cpu: usize) {
+    if let Some(&apic_id) = APIC_IDS.try_get().ok().and_then(|ids| ids.get(cpu)) {
+        lapic::send_ipi(apic_id, lapic::WAKEUP_VECTOR);
+    }
+}
+
+pub(crate) fn init() {
+    let madt = match madt::parse() {
+        Some(madt) => madt,
+        None => {
+            print_err!("[ERR] No MADT, running on one CPU\n");
+            return;
+        }
+    };
+    if !lapic::init(madt.local_apic_address) {
+        print_err!("[ERR] Could not map the local APIC, running on one CPU\n");
+        return;
+    }
+
+    let bsp = lapic::id();
+    let mut ids = vec![bsp];
+    let others = madt.apic_ids.iter().copied().filter(|&id| id != bsp);
+    ids.extend(others.take(MAX_CPUS - 1));
+    let ids = APIC_IDS.get_or_init(|| ids);
+
+    if ids.len() > 1 {
+        match trampoline::install() {
+            Ok(identity_mapped) => {
+                for (cpu, &apic_id) in ids.iter().enumerate().skip(1) {
+                    if !start(cpu, apic_id) {
+                        print_err!("[ERR] CPU {} (APIC ID {}) did not start\n", cpu, apic_id);
+                    }
+                }
+                trampoline::remove(identity_mapped);
+            }
+            Err(err) => print_err!("[ERR] SMP trampoline: {:?}\n", err),
+        }
+    }
+    unsafe {
+        if !crate::CONFIG.quiet_boot {
+            print_ok!("[OK] SMP: {} of {} CPUs online\n", online_cpus(), cpu_count());
+        }
+    }
+}
+
+/// Starts one application processor and waits for it to come up.
+fn start(cpu: usize, apic_id: u8) -> bool {
+    let stack_top = match memory::alloc_stack(AP_STACK_SIZE) {
+        Ok(stack_top) => stack_top,
+        Err(_) => return false,
+    };
+    trampoline::set_params(trampoline::Params {
+        level_4_table: Cr3::read().0.start_address().as_u64(),
+        stack_top: stack_top.as_u64(),
+        entry: ap_main,
+        cpu: cpu as u64,
+    });
+    STARTED.store(false, Ordering::Release);
+
+    let page = (trampoline::ADDRESS / 4096) as u8;
+    lapic::send_init(apic_id);
+    spin_wait(Duration::from_millis(10));
+    lapic::send_startup(apic_id, page);
+    spin_wait(Duration::from_micros(200));
+    if !STARTED.load(Ordering::Acquire) {
+        lapic::send_startup(apic_id, page);
+    }
+
+    let deadline = Instant::now().as_nanos() + START_TIMEOUT.as_nanos() as u64;
+    while Instant::now().as_nanos() < deadline {
+        if STARTED.load(Ordering::Acquire) {
+            return true;
+        }
+        std3::hint::spin_loop();
+    }
+    false
+}
+
+/// Where application processors arrive from the trampoline, on their own
+/// stack and the kernel's page table.
+extern "C" fn ap_main(cpu: u64) -> ! {
+    per_cpu::init(cpu as usize);
+    if gdt::init_ap().is_err() || syscall::init_ap().is_err() {
+        print_err!("[ERR] CPU {}: no memory for interrupt stacks\n", cpu);
+        hlt_loop();
+    }
+    interrupts::load_idt();
+    // Banks and CR4.MCE are per CPU; the count matches the bootstrap one.
+    interrupts::mce::init();
+    lapic::enable();
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+    STARTED.store(true, Ordering::Release);
+    park();
+}
+
+/// Halts until `launch_aps` provides something to run.
+fn park() -> ! {
+    use x86_64::instructions::interrupts;
+    loop {
+        interrupts::disable();
+        match AP_ENTRY.load(Ordering::Acquire) {
+            0 => interrupts::enable_and_hlt(),
+            entry => {
+                interrupts::enable();
+                let entry: fn() -> ! = unsafe { mem::transmute(entry) };
+                entry();
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_bootstrap_processor_is_cpu_0() {
+    assert_eq!(current_cpu(), 0);
+    assert!(online_cpus() <= cpu_count());
+}
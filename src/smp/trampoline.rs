@@ -0,0 +1,173 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Real-mode entry code for application processors.
+//!
+//! A STARTUP IPI starts a CPU in real mode at a page below 1 MiB, so the
+//! code is copied to `ADDRESS` first. It goes straight from real mode to long
+//! mode on the kernel's page table, which only works while the trampoline
+//! page is identity mapped and the level 4 table sits below 4 GiB.
+
+use crate::memory;
+use std3::__bootloader::bootloader::bootinfo::MemoryRegionType;
+use std3::__reexports::x86_64;
+use std3::arch::global_asm;
+use std3::ptr;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+/// Where the trampoline is copied to; the STARTUP IPI vector is its page.
+pub(super) const ADDRESS: u64 = 0x8000;
+/// Offset of `Params` in the trampoline, see the `.org` directives below.
+const PARAMS_OFFSET: u64 = 0x08;
+
+/// What the trampoline hands to `entry`, in the order it reads them.
+#[repr(C)]
+pub(super) struct Params {
+    pub(super) level_4_table: u64,
+    pub(super) stack_top: u64,
+    pub(super) entry: extern "C" fn(u64) -> !,
+    pub(super) cpu: u64,
+}
+
+extern "C" {
+    static rinux_ap_trampoline: u8;
+    static rinux_ap_trampoline_end: u8;
+}
+
+// Addresses are absolute because the code runs at `ADDRESS`, not where it is
+// linked.
+global_asm!(
+    ".pushsection .rodata.rinux_ap_trampoline, \"a\"",
+    ".global rinux_ap_trampoline",
+    ".global rinux_ap_trampoline_end",
+    ".code16",
+    "rinux_ap_trampoline:",
+    "jmp 2f",
+    ".org 0x08",
+    // Params
+    ".quad 0, 0, 0, 0",
+    ".org 0x28",
+    // Temporary GDT: null, 64-bit code at 0x08, data at 0x10
+    ".quad 0",
+    ".quad 0x00AF9A000000FFFF",
+    ".quad 0x00CF92000000FFFF",
+    ".org 0x40",
+    ".word 0x17",
+    ".long 0x8028",
+    "2:",
+    "cli",
+    "cld",
+    "xor ax, ax",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "lgdt [0x8040]",
+    // CR4.PAE
+    "mov eax, cr4",
+    "or eax, 0x20",
+    "mov cr4, eax",
+    "mov eax, dword ptr [0x8008]",
+    "mov cr3, eax",
+    // EFER.LME and EFER.NXE, the kernel's page tables use NO_EXECUTE
+    "mov ecx, 0xC0000080",
+    "rdmsr",
+    "or eax, 0x900",
+    "wrmsr",
+    // CR0.PG, CR0.WP and CR0.PE at once
+    "mov eax, cr0",
+    "or eax, 0x80010001",
+    "mov cr0, eax",
+    // jmp far 0x08:3f with a 32-bit offset
+    ".byte 0x66, 0xEA",
+    ".long 0x8000 + (3f - rinux_ap_trampoline)",
+    ".word 0x08",
+    ".code64",
+    "3:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "xor eax, eax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov rsp, qword ptr [0x8010]",
+    "mov rax, qword ptr [0x8018]",
+    "mov rdi, qword ptr [0x8020]",
+    "call rax",
+    "ud2",
+    "rinux_ap_trampoline_end:",
+    ".popsection",
+);
+
+/// Why the trampoline could not be installed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum InstallError {
+    /// The page at `ADDRESS` is in use according to the memory map.
+    PageInUse,
+    /// The level 4 table is out of reach of the 32-bit `mov cr3`.
+    PageTableAbove4GiB,
+    /// The page could not be mapped.
+    Map,
+}
+
+/// Copies the trampoline to `ADDRESS` and identity maps it. Returns whether
+/// the identity mapping was added and has to be removed by `remove`.
+pub(super) fn install() -> Result<bool, InstallError> {
+    let address = PhysAddr::new(ADDRESS);
+    match memory::region_type(address) {
+        Some(MemoryRegionType::Usable) | Some(MemoryRegionType::Bootloader) => {}
+        _ => return Err(InstallError::PageInUse),
+    }
+    if Cr3::read().0.start_address().as_u64() >= 1 << 32 {
+        return Err(InstallError::PageTableAbove4GiB);
+    }
+
+    let (start, end) = unsafe {
+        (&rinux_ap_trampoline as *const u8, &rinux_ap_trampoline_end as *const u8)
+    };
+    let length = end as usize - start as usize;
+    assert!(length <= 4096, "SMP trampoline does not fit in a page");
+    let virt = memory::map_mmio(address, 4096).map_err(|_| InstallError::Map)?;
+    unsafe { ptr::copy_nonoverlapping(start, virt.as_mut_ptr::<u8>(), length) };
+    memory::identity_map(frame()).map_err(|_| InstallError::Map)
+}
+
+/// Fills in the parameters for the next CPU to start.
+pub(super) fn set_params(params: Params) {
+    let params_address = memory::phys_to_virt(PhysAddr::new(ADDRESS + PARAMS_OFFSET));
+    unsafe { ptr::write_volatile(params_address.as_mut_ptr::<Params>(), params) };
+}
+
+/// Removes the identity mapping again once every CPU is up.
+pub(super) fn remove(identity_mapped: bool) {
+    if identity_mapped {
+        memory::unmap_identity(frame());
+    }
+}
+
+fn frame() -> PhysFrame<Size4KiB> {
+    PhysFrame::containing_address(PhysAddr::new(ADDRESS))
+}
@@ -0,0 +1,156 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Stopping tasks: abort handles drop a task from the outside, cancellation
+//! tokens let tasks notice a request to stop and wind down on their own.
+
+use super::executor::Shared;
+use super::TaskId;
+use std3::future::Future;
+use std3::pin::Pin;
+use std3::sync::atomic::{AtomicBool, Ordering};
+use std3::__reexports::x86_64::instructions::interrupts;
+use std3::sync::{Arc, Mutex};
+use std3::task::{Context, Poll, Waker};
+use std3::{fmt, mem, vec::Vec};
+
+/// Removes a task from its [`Executor`](super::executor::Executor), see
+/// [`JoinHandle::abort_handle`](super::join::JoinHandle::abort_handle).
+#[unstable(feature = "rinuxcore_task", issue = "none")]
+#[derive(Clone)]
+pub struct AbortHandle {
+    id: TaskId,
+    shared: Arc<Shared>,
+}
+
+impl AbortHandle {
+    pub(super) fn new(id: TaskId, shared: Arc<Shared>) -> AbortHandle {
+        AbortHandle { id, shared }
+    }
+
+    /// Drops the task's future, running its destructors, before the executor
+    /// polls its next task. Its [`JoinHandle`](super::join::JoinHandle)
+    /// resolves to [`JoinError::Cancelled`](super::join::JoinError). Does
+    /// nothing if the task has already completed.
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn abort(&self) {
+        self.shared.aborted.push(self.id);
+    }
+}
+
+impl fmt::Debug for AbortHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AbortHandle").field("task", &self.id.0).finish()
+    }
+}
+
+#[derive(Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    /// Only locked with interrupts disabled, as `cancel` may run in a handler
+    wakers: Mutex<Vec<Waker>>,
+}
+
+/// A flag tasks can check or await to learn that they should stop. Clones
+/// share the flag.
+///
+/// ```rust
+/// let token = CancellationToken::new();
+/// let watched = token.clone();
+/// executor.spawn(Task::new(async move {
+///     watched.cancelled().await;
+///     println!("cleaning up");
+/// }));
+/// token.cancel();
+/// ```
+#[unstable(feature = "rinuxcore_task", issue = "none")]
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    state: Arc<TokenState>,
+}
+
+impl CancellationToken {
+    /// A token that is not cancelled yet.
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Sets the flag and wakes every task awaiting [`cancelled`](Self::cancelled).
+    /// Safe to call from interrupt handlers.
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::Release);
+        let wakers = interrupts::without_interrupts(|| mem::take(&mut *self.state.wakers.lock()));
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    /// Whether [`cancel`](Self::cancel) has been called.
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Resolves once the token is cancelled.
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn cancelled(&self) -> Cancelled {
+        Cancelled {
+            token: self.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+/// Future returned by [`CancellationToken::cancelled`].
+#[unstable(feature = "rinuxcore_task", issue = "none")]
+#[derive(Debug)]
+pub struct Cancelled {
+    token: CancellationToken,
+}
+
+impl Future for Cancelled {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        interrupts::without_interrupts(|| {
+            let mut wakers = self.token.state.wakers.lock();
+            // Checked under the lock, so a concurrent `cancel` either is seen
+            // here or finds our waker.
+            if self.token.is_cancelled() {
+                return Poll::Ready(());
+            }
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+    }
+}
@@ -0,0 +1,120 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Deferred work ("bottom halves").
+//!
+//! Interrupt handlers should do as little as possible. [`defer`] lets them
+//! hand a small work item to the [`Executor`](super::executor::Executor),
+//! which runs it later in task context with interrupts enabled. The queue is
+//! allocated once at boot, so deferring never allocates and never blocks.
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use std3::sync::atomic::{AtomicU64, Ordering};
+
+/// Maximum number of work items waiting to run.
+#[unstable(feature = "rinuxcore_deferred", issue = "none")]
+pub const CAPACITY: usize = 256;
+
+static QUEUE: OnceCell<ArrayQueue<Work>> = OnceCell::uninit();
+static OVERFLOWS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+struct Work {
+    func: fn(usize),
+    arg: usize,
+}
+
+/// Why a work item could not be deferred.
+#[unstable(feature = "rinuxcore_deferred", issue = "none")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeferError {
+    /// The queue has not been allocated yet; `rinuxcore::init` does that
+    Uninitialized,
+    /// [`CAPACITY`] items are already waiting
+    Full,
+}
+
+pub(crate) fn init() {
+    QUEUE.init_once(|| ArrayQueue::new(CAPACITY));
+}
+
+/// Queues `func(arg)` to run in task context. Safe to call from interrupt
+/// handlers. Failures are counted in [`overflows`].
+#[unstable(feature = "rinuxcore_deferred", issue = "none")]
+pub fn defer(func: fn(usize), arg: usize) -> Result<(), DeferError> {
+    let result = match QUEUE.try_get() {
+        Ok(queue) => queue.push(Work { func, arg }).map_err(|_| DeferError::Full),
+        Err(_) => Err(DeferError::Uninitialized),
+    };
+    if result.is_err() {
+        OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+    }
+    result
+}
+
+/// Number of work items dropped because they could not be queued.
+#[unstable(feature = "rinuxcore_deferred", issue = "none")]
+pub fn overflows() -> u64 {
+    OVERFLOWS.load(Ordering::Relaxed)
+}
+
+/// Number of work items waiting to run.
+#[unstable(feature = "rinuxcore_deferred", issue = "none")]
+pub fn pending() -> usize {
+    QUEUE.try_get().map_or(0, |queue| queue.len())
+}
+
+/// Runs the work items queued so far and returns how many ran. Items queued
+/// while this runs are left for the next call, so an interrupt storm cannot
+/// starve the executor.
+pub(crate) fn run_pending() -> usize {
+    let queue = match QUEUE.try_get() {
+        Ok(queue) => queue,
+        Err(_) => return 0,
+    };
+    let mut ran = 0;
+    for _ in 0..queue.len() {
+        match queue.pop() {
+            Some(work) => (work.func)(work.arg),
+            None => break,
+        }
+        ran += 1;
+    }
+    ran
+}
+
+#[test_case]
+fn test_deferred_work_runs() {
+    use std3::sync::atomic::AtomicUsize;
+    static SUM: AtomicUsize = AtomicUsize::new(0);
+    fn add(arg: usize) {
+        SUM.fetch_add(arg, Ordering::Relaxed);
+    }
+
+    defer(add, 2).unwrap();
+    defer(add, 3).unwrap();
+    run_pending();
+    assert_eq!(SUM.load(Ordering::Relaxed), 5);
+}
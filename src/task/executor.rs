@@ -25,10 +25,16 @@
 //! Executor for running tasks
 
 use std3::__reexports::x86_64;
-use super::{Task, TaskId};
-use std3::{collections::BTreeMap, sync::Arc, task::Wake};
+use super::join::{self, JoinHandle};
+use super::{deferred, set_current_spawner, set_current_task, Priority, Task, TaskId};
+use crossbeam_queue::SegQueue;
+use std3::fmt;
+use std3::future::Future;
+use std3::mem;
+use std3::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std3::collections::{BTreeMap, BTreeSet};
+use std3::{sync::Arc, task::Wake};
 use std3::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
 
 /// Executor for running tasks.
 /// Make sure you enable the feature:
@@ -60,8 +66,24 @@ use crossbeam_queue::ArrayQueue;
 #[derive(Debug)]
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    task_queue: Arc<ReadyQueue>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
+    capacity: usize,
+    /// Aborted tasks that may still wait in `shared.spawned`
+    unclaimed_aborts: BTreeSet<TaskId>,
+    /// Polls of tasks below each class, so budgets refill once a class has
+    /// given way
+    given_way: [u64; Priority::COUNT],
+    shared: Arc<Shared>,
+}
+
+/// Requests that reach an [`Executor`] from inside its tasks, through
+/// [`Spawner`]s, abort handles and [`super::spawn`]
+#[derive(Debug)]
+pub(crate) struct Shared {
+    pub(super) spawned: SegQueue<Task>,
+    pub(super) aborted: SegQueue<TaskId>,
+    shutdown: AtomicBool,
 }
 impl Default for Executor {
     fn default() -> Self {
@@ -69,34 +91,117 @@ impl Default for Executor {
     }
 }
 impl Executor {
-    /// Create a new executor instance.
-    /// Has a default size of 100 tasks.
+    /// Create a new executor instance with no limit on the number of tasks
     #[unstable(feature = "rinuxcore_task", issue = "none")]
     pub fn new() -> Self {
+        Self::with_capacity(usize::MAX)
+    }
+
+    /// Create a new executor instance holding at most `capacity` tasks.
+    /// Tasks queued through a [`Spawner`] beyond that wait until a running
+    /// task finishes.
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn with_capacity(capacity: usize) -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queue: Arc::new(ReadyQueue::default()),
             waker_cache: BTreeMap::new(),
+            capacity,
+            unclaimed_aborts: BTreeSet::new(),
+            given_way: [0; Priority::COUNT],
+            shared: Arc::new(Shared {
+                spawned: SegQueue::new(),
+                aborted: SegQueue::new(),
+                shutdown: AtomicBool::new(false),
+            }),
         }
     }
 
     /// Spawn a new task
+    ///
+    /// Panics if the executor is at capacity, see [`try_spawn`](Self::try_spawn)
     #[unstable(feature = "rinuxcore_task", issue = "none")]
     pub fn spawn(&mut self, task: Task) {
+        if self.try_spawn(task).is_err() {
+            panic!("executor full");
+        }
+    }
+
+    /// Spawn a new task, handing it back if the executor is at capacity
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn try_spawn(&mut self, task: Task) -> Result<(), Task> {
+        if !self.has_room() {
+            return Err(task);
+        }
         let task_id = task.id;
+        let priority = task.priority;
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        let waker = TaskWaker::new(task_id, priority, self.task_queue.clone());
+        waker.wake_task();
+        self.waker_cache.insert(task_id, waker);
+        Ok(())
+    }
+
+    /// Number of tasks the executor currently holds
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn len(&self) -> usize {
+        self.tasks.len()
     }
 
-    /// Run all tasks in the executor
+    /// Whether the executor holds no tasks
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Spawn a future as a new task, returning a handle that resolves to its
+    /// output
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn spawn_with_handle<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        let (task, handle) = join::spawnable(future, &self.shared);
+        self.spawn(task);
+        handle
+    }
+
+    /// A handle for spawning tasks onto this executor from inside its tasks
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Run all tasks in the executor, along with work deferred by interrupt
+    /// handlers
+    ///
+    /// Never returns: [`Spawner::shutdown`] drops every task, as with
+    /// [`run_until_shutdown`](Self::run_until_shutdown), and the executor
+    /// then goes on with whatever is spawned afterwards.
     #[unstable(feature = "rinuxcore_task", issue = "none")]
     pub fn run(&mut self) -> ! {
         loop {
+            self.run_until_shutdown();
+        }
+    }
+
+    /// Like [`run`](Self::run), but returns once [`Spawner::shutdown`] has
+    /// been called, after dropping every task
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn run_until_shutdown(&mut self) {
+        loop {
+            deferred::run_pending();
             self.run_ready_tasks();
+            if self.shared.shutdown.swap(false, Ordering::AcqRel) {
+                break;
+            }
             self.sleep_if_idle();
         }
+        self.drop_tasks();
     }
 
     /// Runs first task in the executor's queue.
@@ -147,6 +252,7 @@ impl Executor {
     /// ```
     #[unstable(feature = "rinuxcore_task", issue = "none")]
     pub fn run_first_task_in_queue(&mut self) -> () {
+        self.take_spawned();
         let task_id = self.task_queue.pop().expect("queue empty");
         self.run_task(task_id);
     }
@@ -155,18 +261,35 @@ impl Executor {
     fn run_task(&mut self, task_id: TaskId) -> () {
         let Self {
             tasks,
-            task_queue,
             waker_cache,
+            given_way,
+            shared,
+            ..
         } = self;
         let task = match tasks.get_mut(&task_id) {
             Some(task) => task,
             None => return,
         };
-        let waker = waker_cache
-            .entry(task_id)
-            .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-        let mut context = Context::from_waker(waker);
-        match task.poll(&mut context) {
+        let waker = match waker_cache.get(&task_id) {
+            Some(waker) => waker,
+            None => return,
+        };
+        let level = waker.level();
+        for count in &mut given_way[..level as usize] {
+            *count += 1;
+        }
+        let next = task.charge(level, given_way[task.priority() as usize]);
+        waker.level.store(next as u8, Ordering::Release);
+        // Cleared before polling so a wake during the poll queues it again
+        waker.queued.store(false, Ordering::Release);
+        let waker = Waker::from(waker.clone());
+        let mut context = Context::from_waker(&waker);
+        set_current_task(Some(task_id));
+        let previous = set_current_spawner(Arc::as_ptr(shared));
+        let poll = task.poll(&mut context);
+        set_current_spawner(previous);
+        set_current_task(None);
+        match poll {
             Poll::Ready(()) => {
                 tasks.remove(&task_id);
                 waker_cache.remove(&task_id);
@@ -175,39 +298,179 @@ impl Executor {
         }
     }
 
+    /// Stops early on shutdown, so tasks that keep waking each other cannot
+    /// hold it off.
     fn run_ready_tasks(&mut self) {
-        while let Some(task_id) = self.task_queue.pop() {
-            self.run_task(task_id);
+        loop {
+            if self.shared.shutdown.load(Ordering::Acquire) {
+                break;
+            }
+            self.take_spawned();
+            self.take_aborted();
+            match self.task_queue.pop() {
+                Some(task_id) => self.run_task(task_id),
+                None => break,
+            }
+        }
+    }
+
+    fn has_room(&self) -> bool {
+        self.tasks.len() < self.capacity
+    }
+
+    /// Spawned tasks stay queued while the executor is at capacity
+    fn take_spawned(&mut self) {
+        while self.has_room() {
+            match self.shared.spawned.pop() {
+                Some(task) if self.unclaimed_aborts.remove(&task.id) => drop(task),
+                Some(task) => self.spawn(task),
+                None => {
+                    // Whatever is left belongs to tasks that already finished
+                    self.unclaimed_aborts.clear();
+                    break;
+                }
+            }
         }
     }
 
+    fn take_aborted(&mut self) {
+        while let Some(task_id) = self.shared.aborted.pop() {
+            if self.tasks.remove(&task_id).is_some() {
+                self.waker_cache.remove(&task_id);
+            } else if !self.shared.spawned.is_empty() {
+                // Not taken yet while the executor is at capacity
+                self.unclaimed_aborts.insert(task_id);
+            }
+        }
+    }
+
+    /// Drops every task, running their destructors. Dropped tasks may wake
+    /// others, so the queues are cleared last.
+    fn drop_tasks(&mut self) {
+        drop(mem::take(&mut self.tasks));
+        while self.shared.spawned.pop().is_some() {}
+        self.waker_cache.clear();
+        while self.task_queue.pop().is_some() {}
+        while self.shared.aborted.pop().is_some() {}
+        self.unclaimed_aborts.clear();
+    }
+
     fn sleep_if_idle(&self) {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
         interrupts::disable();
-        if self.task_queue.is_empty() {
-            enable_and_hlt();
+        let shared = &self.shared;
+        let idle = self.task_queue.is_empty()
+            && (shared.spawned.is_empty() || !self.has_room())
+            && shared.aborted.is_empty()
+            && !shared.shutdown.load(Ordering::Acquire);
+        if idle && deferred::pending() == 0 {
+            if crate::thread::others_ready() {
+                interrupts::enable();
+                crate::thread::yield_now();
+            } else {
+                enable_and_hlt();
+            }
         } else {
             interrupts::enable();
         }
     }
 }
 
+/// Spawns tasks onto the [`Executor`] it came from, which is how running tasks
+/// start new ones. Cheap to clone.
+///
+/// ```rust
+/// let spawner = executor.spawner();
+/// executor.spawn(Task::new(async move {
+///     spawner.spawn(Task::new(async { println!("child") }));
+/// }));
+/// ```
+#[unstable(feature = "rinuxcore_task", issue = "none")]
+#[derive(Clone)]
+pub struct Spawner {
+    shared: Arc<Shared>,
+}
+
+impl Spawner {
+    /// Queue a task; the executor picks it up before polling its next task
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn spawn(&self, task: Task) {
+        self.shared.spawned.push(task);
+    }
+
+    /// Queue a future as a new task, returning a handle to its output
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn spawn_with_handle<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        let (task, handle) = join::spawnable(future, &self.shared);
+        self.spawn(task);
+        handle
+    }
+
+    /// Makes [`Executor::run_until_shutdown`] return, dropping every task,
+    /// once the running task yields
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn shutdown(&self) {
+        self.shared.shutdown.store(true, Ordering::Release);
+    }
+}
+
+impl fmt::Debug for Spawner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Spawner").field("queued", &self.shared.spawned.len()).finish()
+    }
+}
+
+/// Woken tasks, one FIFO per [`Priority`]
+#[derive(Debug, Default)]
+struct ReadyQueue([SegQueue<TaskId>; Priority::COUNT]);
+
+impl ReadyQueue {
+    fn push(&self, level: Priority, task_id: TaskId) {
+        self.0[level as usize].push(task_id);
+    }
+
+    fn pop(&self) -> Option<TaskId> {
+        self.0.iter().find_map(SegQueue::pop)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.iter().all(SegQueue::is_empty)
+    }
+}
+
+#[derive(Debug)]
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    task_queue: Arc<ReadyQueue>,
+    queued: AtomicBool,
+    /// Queue the next wake goes to, the task's priority unless it is over
+    /// budget
+    level: AtomicU8,
 }
 
 impl TaskWaker {
-    #[allow(clippy::new_ret_no_self)]
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
+    fn new(task_id: TaskId, priority: Priority, task_queue: Arc<ReadyQueue>) -> Arc<Self> {
+        Arc::new(TaskWaker {
             task_id,
             task_queue,
-        }))
+            queued: AtomicBool::new(false),
+            level: AtomicU8::new(priority as u8),
+        })
+    }
+
+    fn level(&self) -> Priority {
+        Priority::from_index(self.level.load(Ordering::Acquire))
     }
 
+    /// Queues the task unless it is already waiting to be polled, so the
+    /// ready queue never holds more entries than there are tasks
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.task_queue.push(self.level(), self.task_id);
+        }
     }
 }
 
@@ -220,3 +483,166 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+#[test_case]
+fn test_join_handle_from_another_task() {
+    use std3::sync::atomic::{AtomicBool, Ordering};
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = Executor::new();
+    let handle = executor.spawn_with_handle(async { 6 * 7 });
+    executor.spawn(Task::new(async move {
+        assert_eq!(handle.await, Ok(42));
+        DONE.store(true, Ordering::SeqCst);
+    }));
+    executor.run_ready_tasks();
+    assert!(DONE.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn test_tasks_spawn_children() {
+    use std3::sync::atomic::{AtomicU64, Ordering};
+    static SUM: AtomicU64 = AtomicU64::new(0);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.spawn(Task::new(async move {
+        spawner.spawn(Task::new(async {
+            SUM.fetch_add(1, Ordering::SeqCst);
+        }));
+        let child = super::spawn(async { 2 });
+        SUM.fetch_add(child.await.unwrap(), Ordering::SeqCst);
+    }));
+    executor.run_ready_tasks();
+    assert_eq!(SUM.load(Ordering::SeqCst), 3);
+}
+
+#[test_case]
+fn test_abort_and_shutdown() {
+    use super::join::JoinError;
+    use std3::sync::atomic::{AtomicU64, Ordering};
+    static CANCELLED: AtomicU64 = AtomicU64::new(0);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let forever = executor.spawn_with_handle(std3::future::pending::<()>());
+    let abort = forever.abort_handle();
+    let never = executor.spawn_with_handle(std3::future::pending::<()>());
+    executor.spawn(Task::new(async move {
+        abort.abort();
+        if forever.await == Err(JoinError::Cancelled) {
+            CANCELLED.fetch_add(1, Ordering::SeqCst);
+        }
+        spawner.shutdown();
+    }));
+    executor.run_until_shutdown();
+    assert_eq!(CANCELLED.load(Ordering::SeqCst), 1);
+    assert!(never.is_finished());
+}
+
+#[test_case]
+fn test_capacity_backpressure() {
+    let mut executor = Executor::with_capacity(1);
+    let spawner = executor.spawner();
+    let first = executor.spawn_with_handle(async { 1 });
+    assert!(executor.try_spawn(Task::new(async {})).is_err());
+    let second = spawner.spawn_with_handle(async { 2 });
+    executor.run_ready_tasks();
+    assert!(first.is_finished() && second.is_finished());
+    assert!(executor.is_empty());
+}
+
+#[test_case]
+fn test_abort_task_waiting_for_capacity() {
+    use std3::sync::atomic::{AtomicBool, Ordering};
+    static RAN: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = Executor::with_capacity(1);
+    let spawner = executor.spawner();
+    let blocker = executor.spawn_with_handle(std3::future::pending::<()>());
+    let queued = spawner.spawn_with_handle(async { RAN.store(true, Ordering::SeqCst) });
+    queued.abort();
+    executor.run_ready_tasks();
+    blocker.abort();
+    executor.run_ready_tasks();
+    executor.run_ready_tasks();
+    assert!(!RAN.load(Ordering::SeqCst));
+    assert!(executor.is_empty());
+    assert!(queued.is_finished());
+}
+
+#[test_case]
+fn test_repeated_wakes_queue_once() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(std3::future::pending::<()>()));
+    executor.run_ready_tasks();
+    let waker = Waker::from(executor.waker_cache.values().next().unwrap().clone());
+    for _ in 0..1000 {
+        waker.wake_by_ref();
+    }
+    assert!(executor.task_queue.pop().is_some());
+    assert!(executor.task_queue.is_empty());
+}
+
+#[test_case]
+fn test_priorities_and_budgets() {
+    use std3::sync::atomic::{AtomicU32, Ordering};
+    use std3::sync::Mutex;
+    use std3::vec::Vec;
+    static ORDER: Mutex<Vec<Priority>> = Mutex::new(Vec::new());
+    static IO_POLLS: AtomicU32 = AtomicU32::new(0);
+
+    let mut executor = Executor::new();
+    for priority in [Priority::Background, Priority::Normal, Priority::Io] {
+        let task = Task::new(async move { ORDER.lock().push(priority) });
+        executor.spawn(task.with_priority(priority));
+    }
+    executor.run_ready_tasks();
+    assert_eq!(*ORDER.lock(), [Priority::Io, Priority::Normal, Priority::Background]);
+
+    let busy = async {
+        for _ in 0..4 {
+            IO_POLLS.fetch_add(1, Ordering::SeqCst);
+            super::yield_now().await;
+        }
+    };
+    executor.spawn(Task::new(busy).with_priority(Priority::Io).with_budget(2));
+    executor.spawn(Task::new(async {
+        // The I/O task was demoted after using its budget of two polls
+        assert_eq!(IO_POLLS.load(Ordering::SeqCst), 2);
+    }));
+    executor.run_ready_tasks();
+    assert!(executor.is_empty());
+}
+
+#[test_case]
+fn test_budgets_shared_by_busy_tasks() {
+    use std3::sync::atomic::{AtomicU32, Ordering};
+    static IO_POLLS: AtomicU32 = AtomicU32::new(0);
+    static NORMAL_SAW: AtomicU32 = AtomicU32::new(0);
+
+    let mut executor = Executor::new();
+    for _ in 0..2 {
+        let busy = async {
+            for _ in 0..100 {
+                IO_POLLS.fetch_add(1, Ordering::SeqCst);
+                super::yield_now().await;
+            }
+        };
+        executor.spawn(Task::new(busy).with_priority(Priority::Io).with_budget(2));
+    }
+    executor.spawn(Task::new(async {
+        NORMAL_SAW.store(IO_POLLS.load(Ordering::SeqCst), Ordering::SeqCst);
+    }));
+    executor.spawn(
+        Task::new(async {
+            // Demoted I/O tasks wait in Normal, not down here
+            assert_eq!(IO_POLLS.load(Ordering::SeqCst), 200);
+        })
+        .with_priority(Priority::Background),
+    );
+    executor.run_ready_tasks();
+    // Alternating between the two did not keep refilling their budgets
+    assert_eq!(NORMAL_SAW.load(Ordering::SeqCst), 4);
+    assert!(executor.is_empty());
+}
@@ -0,0 +1,205 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Turning interrupts into async streams.
+//!
+//! An [`IrqStream`] lives in a `static`. The interrupt handler calls
+//! [`IrqStream::push`], which never allocates or blocks; a task reads the
+//! values through [`IrqStream::reader`].
+//!
+//! ```rust
+//! use futures_util::stream::StreamExt;
+//! use rinuxcore::task::irq_stream::IrqStream;
+//!
+//! static PACKETS: IrqStream<u8> = IrqStream::new(64);
+//!
+//! fn mouse_interrupt(byte: u8) {
+//!     let _ = PACKETS.push(byte);
+//! }
+//!
+//! async fn mouse_task() {
+//!     let mut packets = PACKETS.reader();
+//!     while let Some(byte) = packets.next().await {
+//!         // ...
+//!     }
+//! }
+//! ```
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+use std3::{
+    fmt,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+/// A bounded, lock-free queue fed by an interrupt handler and drained as a
+/// [`Stream`].
+#[unstable(feature = "rinuxcore_irq_stream", issue = "none")]
+pub struct IrqStream<T> {
+    queue: OnceCell<ArrayQueue<T>>,
+    waker: AtomicWaker,
+    overflows: AtomicU64,
+    capacity: usize,
+}
+
+impl<T> IrqStream<T> {
+    /// Creates a stream holding up to `capacity` values. The queue itself is
+    /// allocated by [`init`](Self::init) or [`reader`](Self::reader), once the
+    /// heap is up.
+    ///
+    /// Panics if `capacity` is zero, which in a `static` fails the build.
+    #[unstable(feature = "rinuxcore_irq_stream", issue = "none")]
+    pub const fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "IrqStream capacity must not be zero");
+        IrqStream {
+            queue: OnceCell::uninit(),
+            waker: AtomicWaker::new(),
+            overflows: AtomicU64::new(0),
+            capacity,
+        }
+    }
+
+    /// Allocates the queue. Returns `false` if it already was.
+    #[unstable(feature = "rinuxcore_irq_stream", issue = "none")]
+    pub fn init(&self) -> bool {
+        let capacity = self.capacity;
+        self.queue.try_init_once(|| ArrayQueue::new(capacity)).is_ok()
+    }
+
+    /// Queues `value` and wakes the reader. Safe to call from interrupt
+    /// handlers. Gives `value` back, and counts an overflow, if the queue is
+    /// full or not allocated yet.
+    #[unstable(feature = "rinuxcore_irq_stream", issue = "none")]
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let result = match self.queue.try_get() {
+            Ok(queue) => queue.push(value),
+            Err(_) => Err(value),
+        };
+        match result {
+            Ok(()) => self.waker.wake(),
+            Err(_) => {
+                self.overflows.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+
+    /// Number of values dropped by [`push`](Self::push).
+    #[unstable(feature = "rinuxcore_irq_stream", issue = "none")]
+    pub fn overflows(&self) -> u64 {
+        self.overflows.load(Ordering::Relaxed)
+    }
+
+    /// Number of values waiting to be read.
+    #[unstable(feature = "rinuxcore_irq_stream", issue = "none")]
+    pub fn len(&self) -> usize {
+        self.queue.try_get().map_or(0, |queue| queue.len())
+    }
+
+    /// Returns `true` if no values are waiting.
+    #[unstable(feature = "rinuxcore_irq_stream", issue = "none")]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Maximum number of values waiting to be read.
+    #[unstable(feature = "rinuxcore_irq_stream", issue = "none")]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns a [`Stream`] over the queued values, allocating the queue if
+    /// needed. Only the last reader to be polled is woken, so use one at a
+    /// time.
+    #[unstable(feature = "rinuxcore_irq_stream", issue = "none")]
+    pub fn reader(&'static self) -> IrqStreamReader<T> {
+        self.init();
+        IrqStreamReader { stream: self }
+    }
+
+    /// Takes the next value, or registers `cx` to be woken when one arrives.
+    #[unstable(feature = "rinuxcore_irq_stream", issue = "none")]
+    pub fn poll_next(&self, cx: &mut Context) -> Poll<Option<T>> {
+        let queue = self.queue.try_get().expect("irq stream not initialized");
+
+        if let Some(value) = queue.pop() {
+            return Poll::Ready(Some(value));
+        }
+
+        self.waker.register(cx.waker());
+        match queue.pop() {
+            Some(value) => {
+                self.waker.take();
+                Poll::Ready(Some(value))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T> fmt::Debug for IrqStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IrqStream")
+            .field("len", &self.len())
+            .field("capacity", &self.capacity)
+            .field("overflows", &self.overflows())
+            .finish()
+    }
+}
+
+/// Reading end of an [`IrqStream`], see [`IrqStream::reader`].
+#[unstable(feature = "rinuxcore_irq_stream", issue = "none")]
+pub struct IrqStreamReader<T: 'static> {
+    stream: &'static IrqStream<T>,
+}
+
+impl<T> Stream for IrqStreamReader<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.stream.poll_next(cx)
+    }
+}
+
+impl<T> fmt::Debug for IrqStreamReader<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("IrqStreamReader").field(self.stream).finish()
+    }
+}
+
+#[test_case]
+fn test_irq_stream_overflow() {
+    static STREAM: IrqStream<u32> = IrqStream::new(2);
+
+    assert_eq!(STREAM.push(1), Err(1));
+    STREAM.init();
+    assert_eq!(STREAM.push(2), Ok(()));
+    assert_eq!(STREAM.push(3), Ok(()));
+    assert_eq!(STREAM.push(4), Err(4));
+    assert_eq!(STREAM.overflows(), 2);
+    assert_eq!(STREAM.len(), 2);
+}
@@ -0,0 +1,177 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Handles for awaiting the output of a spawned task.
+
+use super::cancel::AbortHandle;
+use super::executor::Shared;
+use super::Task;
+use std3::future::Future;
+use std3::pin::Pin;
+use std3::sync::{Arc, Mutex};
+use std3::task::{Context, Poll, Waker};
+use std3::fmt;
+
+/// Why a [`JoinHandle`] has no output for its task.
+#[unstable(feature = "rinuxcore_task", issue = "none")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum JoinError {
+    /// The task was aborted, or dropped by an executor shutdown, before it
+    /// completed.
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+        }
+    }
+}
+
+struct State<T> {
+    output: Option<Result<T, JoinError>>,
+    finished: bool,
+    waker: Option<Waker>,
+}
+
+/// Resolves to the output of a task spawned with
+/// [`Executor::spawn_with_handle`](super::executor::Executor::spawn_with_handle),
+/// or to [`JoinError::Cancelled`] if the task was dropped first.
+///
+/// Can be awaited from another task on the same executor:
+///
+/// ```rust
+/// let handle = executor.spawn_with_handle(async { 6 * 7 });
+/// executor.spawn(Task::new(async move {
+///     assert_eq!(handle.await, Ok(42));
+/// }));
+/// ```
+#[unstable(feature = "rinuxcore_task", issue = "none")]
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<State<T>>>,
+    abort: AbortHandle,
+}
+
+impl<T> JoinHandle<T> {
+    /// Whether the task has completed or was cancelled. Stays `true` after
+    /// its output was taken.
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().finished
+    }
+
+    /// Aborts the task, see [`AbortHandle::abort`].
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn abort(&self) {
+        self.abort.abort();
+    }
+
+    /// A handle that can abort the task without owning its output.
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None if state.finished => panic!("JoinHandle polled after completion"),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JoinHandle")
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+
+/// Hands the task's outcome to its handle. Moved into the task's future, so
+/// dropping the task unfinished drops this too and reports the cancellation.
+struct Completion<T> {
+    state: Option<Arc<Mutex<State<T>>>>,
+}
+
+impl<T> Completion<T> {
+    fn complete(mut self, output: T) {
+        if let Some(state) = self.state.take() {
+            finish(&state, Ok(output));
+        }
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            finish(&state, Err(JoinError::Cancelled));
+        }
+    }
+}
+
+fn finish<T>(state: &Mutex<State<T>>, output: Result<T, JoinError>) {
+    let waker = {
+        let mut state = state.lock();
+        state.output = Some(output);
+        state.finished = true;
+        state.waker.take()
+    };
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// Wraps `future` into a task for the executor owning `shared`, and a handle
+/// for its output.
+pub(crate) fn spawnable<F>(future: F, shared: &Arc<Shared>) -> (Task, JoinHandle<F::Output>)
+where
+    F: Future + 'static,
+{
+    let state = Arc::new(Mutex::new(State {
+        output: None,
+        finished: false,
+        waker: None,
+    }));
+    let completion = Completion {
+        state: Some(state.clone()),
+    };
+    let task = Task::new(async move {
+        let output = future.await;
+        completion.complete(output);
+    });
+    let abort = AbortHandle::new(task.id, shared.clone());
+    (task, JoinHandle { state, abort })
+}
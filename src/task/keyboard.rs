@@ -24,32 +24,43 @@
 
 //! Keyboard utilities
 
+use super::{deferred, irq_stream::IrqStream};
 use crate::{print, print_err, vga_buffer::print_ok};
-use conquer_once::spin::OnceCell;
 use std3::{
     pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
     task::{Context, Poll},
 };
-use crossbeam_queue::ArrayQueue;
-use futures_util::{
-    stream::{Stream, StreamExt},
-    task::AtomicWaker,
-};
+use futures_util::stream::{Stream, StreamExt};
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
-static WAKER: AtomicWaker = AtomicWaker::new();
+static SCANCODES: IrqStream<u8> = IrqStream::new(100);
+static REPORT_PENDING: AtomicBool = AtomicBool::new(false);
 
+/// Called from the keyboard interrupt handler, so it must not print.
 pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if queue.push(scancode).is_err() {
-            print_err!("[ERR] scancode queue full; dropping keyboard input\n");
-        } else {
-            WAKER.wake();
-        }
-    } else {
-        print_err!("[ERR] scancode queue uninitialized\n");
+    if SCANCODES.push(scancode).is_ok() {
+        return;
     }
+    if !REPORT_PENDING.swap(true, Ordering::Relaxed)
+        && deferred::defer(report_dropped, 0).is_err()
+    {
+        REPORT_PENDING.store(false, Ordering::Relaxed);
+    }
+}
+
+fn report_dropped(_: usize) {
+    REPORT_PENDING.store(false, Ordering::Relaxed);
+    print_err!(
+        "[ERR] scancode queue full or uninitialized; {} keyboard inputs dropped so far\n",
+        dropped_scancodes()
+    );
+}
+
+/// Number of scancodes dropped because the queue was full or not set up yet
+#[unstable(feature = "rinuxcore_keyboard", issue = "none")]
+pub fn dropped_scancodes() -> u64 {
+    SCANCODES.overflows()
 }
 
 /// Keyboard presses stream
@@ -64,18 +75,15 @@ impl ScancodeStream {
     /// Create a new scancode stream
     #[unstable(feature = "rinuxcore_keyboard", issue = "none")]
     pub fn new() -> Self {
-        match SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100)) {
-            Ok(_) => {
-                unsafe {
-                    if !crate::CONFIG.quiet_boot {
-                        print_ok!("[OK] Scancode initialized\n");
-                    };
+        if SCANCODES.init() {
+            unsafe {
+                if !crate::CONFIG.quiet_boot {
+                    print_ok!("[OK] Scancode initialized\n");
                 };
-            }
-            Err(_) => {
-                print_err!("[ERR] ScancodeStream::new should only be called once");
-                panic!("ScancodeStream::new should only be called once");
-            }
+            };
+        } else {
+            print_err!("[ERR] ScancodeStream::new should only be called once");
+            panic!("ScancodeStream::new should only be called once");
         }
         ScancodeStream { _private: () }
     }
@@ -86,22 +94,7 @@ impl Stream for ScancodeStream {
     type Item = u8;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
-        let queue = SCANCODE_QUEUE
-            .try_get()
-            .expect("scancode queue not initialized");
-
-        if let Some(scancode) = queue.pop() {
-            return Poll::Ready(Some(scancode));
-        }
-
-        WAKER.register(cx.waker());
-        match queue.pop() {
-            Some(scancode) => {
-                WAKER.take();
-                Poll::Ready(Some(scancode))
-            }
-            None => Poll::Pending,
-        }
+        SCANCODES.poll_next(cx)
     }
 }
 
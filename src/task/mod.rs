@@ -24,26 +24,77 @@
 
 //! Tasking utilities
 
+use executor::Shared;
+use join::JoinHandle;
 use std3::boxed::Box;
 use std3::{
     future::Future,
     pin::Pin,
-    sync::atomic::{AtomicU64, Ordering},
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+    sync::Arc,
     task::{Context, Poll},
 };
 
+#[unstable(feature = "rinuxcore_task", issue = "none")]
+pub mod cancel;
+#[unstable(feature = "rinuxcore_deferred", issue = "none")]
+pub mod deferred;
 #[unstable(feature = "rinuxcore_task", issue = "none")]
 pub mod executor;
+#[unstable(feature = "rinuxcore_irq_stream", issue = "none")]
+pub mod irq_stream;
+#[unstable(feature = "rinuxcore_task", issue = "none")]
+pub mod join;
 #[unstable(feature = "rinuxcore_keyboard", issue = "none")]
 pub mod keyboard;
+#[unstable(feature = "rinuxcore_multicore", issue = "none")]
+pub mod multicore;
 #[unstable(feature = "rinuxcore_task", issue = "none")]
 pub mod simple_executor;
 
+/// Scheduling class of a [`Task`]. An [`executor::Executor`] polls every
+/// ready task of a class before any of the classes below it.
+#[unstable(feature = "rinuxcore_task", issue = "none")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Priority {
+    /// Tasks driven by interrupts, like device I/O
+    Io,
+    /// The default class
+    #[default]
+    Normal,
+    /// Work that can wait until nothing else is ready
+    Background,
+}
+
+impl Priority {
+    const COUNT: usize = 3;
+
+    fn from_index(index: u8) -> Priority {
+        match index {
+            0 => Priority::Io,
+            1 => Priority::Normal,
+            _ => Priority::Background,
+        }
+    }
+
+    /// The class below, or `Background` itself
+    fn below(self) -> Priority {
+        Priority::from_index(self as u8 + 1)
+    }
+}
+
 /// Task, includes a future and a task ID
 #[unstable(feature = "rinuxcore_task", issue = "none")]
 pub struct Task {
     id: TaskId,
     future: Pin<Box<dyn Future<Output = ()>>>,
+    priority: Priority,
+    budget: Option<u32>,
+    remaining: u32,
+    /// The executor's count of polls below `priority` when the budget was
+    /// last refilled
+    given_way: u64,
 }
 
 impl Task {
@@ -53,6 +104,62 @@ impl Task {
         Task {
             id: TaskId::new(),
             future: Box::pin(future),
+            priority: Priority::Normal,
+            budget: None,
+            remaining: 0,
+            given_way: 0,
+        }
+    }
+
+    /// Sets the class the task is scheduled in, [`Priority::Normal`] by
+    /// default
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn with_priority(mut self, priority: Priority) -> Task {
+        self.priority = priority;
+        self
+    }
+
+    /// Lets the task be polled at most `polls` times in its class before a
+    /// lower class gets a turn. After that it waits in the class below,
+    /// behind the tasks already queued there, so tasks that keep waking
+    /// themselves can't starve the classes below theirs.
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn with_budget(mut self, polls: u32) -> Task {
+        let polls = polls.max(1);
+        self.budget = Some(polls);
+        self.remaining = polls;
+        self
+    }
+
+    /// The class the task is scheduled in
+    #[unstable(feature = "rinuxcore_task", issue = "none")]
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Charges a poll against the budget and returns the class the task's
+    /// next wake is queued in, given the class it was just taken from and
+    /// how many tasks below its own class the executor has polled so far
+    fn charge(&mut self, level: Priority, given_way: u64) -> Priority {
+        let budget = match self.budget {
+            Some(budget) => budget,
+            None => return self.priority,
+        };
+        if given_way != self.given_way {
+            // A lower class ran since the last refill
+            self.given_way = given_way;
+            self.remaining = budget;
+        }
+        if level != self.priority {
+            // Waited its turn in the class below
+            return self.priority;
+        }
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.remaining = budget;
+            self.priority.below()
+        } else {
+            self.priority
         }
     }
 
@@ -67,7 +174,7 @@ impl Task {
 use std3::fmt::{Debug, Formatter, Error as FmtError};
 impl Debug for Task {
     fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
-        write!(f, "Task {{ id: {} }}", self.id.0)
+        write!(f, "Task {{ id: {}, priority: {:?} }}", self.id.0, self.priority)
     }
 }
 impl PartialEq for Task {
@@ -102,6 +209,105 @@ impl Ord for Task {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
 
+const NO_TASK: u64 = u64::MAX;
+crate::per_cpu! {
+    static CURRENT_TASK: AtomicU64 = AtomicU64::new(NO_TASK);
+    /// Shared state of the executor polling on this CPU, null outside a poll
+    static CURRENT_SPAWNER: AtomicPtr<Shared> = AtomicPtr::new(ptr::null_mut());
+}
+
+/// ID of the task an [`executor::Executor`] is polling on the running CPU,
+/// if any
+#[unstable(feature = "rinuxcore_task", issue = "none")]
+pub fn current_task_id() -> Option<u64> {
+    match CURRENT_TASK.get().load(Ordering::Relaxed) {
+        NO_TASK => None,
+        id => Some(id),
+    }
+}
+
+fn set_current_task(task_id: Option<TaskId>) {
+    let id = task_id.map_or(NO_TASK, |id| id.0);
+    CURRENT_TASK.get().store(id, Ordering::Relaxed);
+}
+
+/// Makes `shared` the target of [`spawn`] until the next call, returning the
+/// previous one. `shared` must stay alive until then.
+fn set_current_spawner(shared: *const Shared) -> *const Shared {
+    CURRENT_SPAWNER.get().swap(shared as *mut _, Ordering::Relaxed)
+}
+
+/// The running CPU's current task and spawner, which belong to the kernel
+/// thread polling them rather than to the CPU.
+#[derive(Debug)]
+pub(crate) struct PollContext {
+    task: u64,
+    spawner: *mut Shared,
+}
+
+/// Clears the current task and spawner before a thread switch, returning
+/// them for [`restore_poll_context`] once the thread runs again.
+pub(crate) fn take_poll_context() -> PollContext {
+    PollContext {
+        task: CURRENT_TASK.get().swap(NO_TASK, Ordering::Relaxed),
+        spawner: CURRENT_SPAWNER.get().swap(ptr::null_mut(), Ordering::Relaxed),
+    }
+}
+
+pub(crate) fn restore_poll_context(context: PollContext) {
+    CURRENT_TASK.get().store(context.task, Ordering::Relaxed);
+    CURRENT_SPAWNER.get().store(context.spawner, Ordering::Relaxed);
+}
+
+/// Spawns `future` on the [`executor::Executor`] polling the calling task.
+///
+/// # Panics
+///
+/// Panics if called from outside a task run by an `Executor`.
+#[unstable(feature = "rinuxcore_task", issue = "none")]
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+{
+    let shared = CURRENT_SPAWNER.get().load(Ordering::Relaxed);
+    assert!(!shared.is_null(), "task::spawn called outside of an executor");
+    // The executor keeps its `Arc` alive while it polls.
+    let shared = unsafe {
+        Arc::increment_strong_count(shared);
+        Arc::from_raw(shared)
+    };
+    let (task, handle) = join::spawnable(future, &shared);
+    shared.spawned.push(task);
+    handle
+}
+
+/// Returns to the executor once, letting it poll the other ready tasks
+/// before the calling task continues.
+#[unstable(feature = "rinuxcore_task", issue = "none")]
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// Future returned by [`yield_now`].
+#[unstable(feature = "rinuxcore_task", issue = "none")]
+#[derive(Debug)]
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
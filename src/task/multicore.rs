@@ -0,0 +1,216 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! A work-stealing executor that runs on every CPU.
+//!
+//! Each CPU has its own run queue. A woken task goes back on the queue of the
+//! CPU that last polled it, and a CPU whose queue is empty steals from the
+//! others before it halts. Halted CPUs are woken with an IPI when work
+//! arrives for them, or when work arrives for a busy CPU and could be stolen.
+//!
+//! Unlike [`Executor`](super::executor::Executor) this is a single global
+//! executor, and tasks must be `Send` since they move between CPUs:
+//!
+//! ```rust
+//! use rinuxcore::task::multicore;
+//!
+//! multicore::spawn(async {
+//!     rinuxcore::println!("Hello from CPU {}", rinuxcore::per_cpu::cpu_id());
+//! });
+//! multicore::run()
+//! ```
+
+use super::{deferred, set_current_task, TaskId};
+use crate::per_cpu::{cpu_id, Counter};
+use crate::{smp, thread};
+use crossbeam_queue::SegQueue;
+use std3::__reexports::x86_64;
+use std3::boxed::Box;
+use std3::future::Future;
+use std3::pin::Pin;
+use std3::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use std3::sync::{Arc, Mutex};
+use std3::task::{Context, Wake, Waker};
+use x86_64::instructions::interrupts;
+
+struct Job {
+    id: TaskId,
+    /// `None` once the future has completed.
+    future: Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    /// CPU whose queue the job goes on when woken.
+    home: AtomicUsize,
+    /// Set while the job is on a queue, so a wake storm queues it only once.
+    queued: AtomicBool,
+}
+
+crate::per_cpu! {
+    static QUEUES: SegQueue<Arc<Job>> = SegQueue::new();
+    /// Set while the CPU is about to halt or halted.
+    static IDLE: AtomicBool = AtomicBool::new(false);
+    static POLLS: Counter = Counter::new();
+    static STEALS: Counter = Counter::new();
+}
+
+impl Wake for Job {
+    fn wake(self: Arc<Self>) {
+        schedule(self);
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        schedule(self.clone());
+    }
+}
+
+/// Queues `future` on the running CPU.
+#[unstable(feature = "rinuxcore_multicore", issue = "none")]
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    schedule(Arc::new(Job {
+        id: TaskId::new(),
+        future: Mutex::new(Some(Box::pin(future))),
+        home: AtomicUsize::new(cpu_id()),
+        queued: AtomicBool::new(false),
+    }));
+}
+
+/// Starts the executor on every online application processor and runs it on
+/// this CPU too. Later calls on other CPUs just join in.
+#[unstable(feature = "rinuxcore_multicore", issue = "none")]
+pub fn run() -> ! {
+    smp::launch_aps(worker);
+    worker()
+}
+
+/// Tasks polled, summed over every CPU.
+#[unstable(feature = "rinuxcore_multicore", issue = "none")]
+pub fn polls() -> u64 {
+    POLLS.total()
+}
+
+/// Tasks taken from another CPU's queue, summed over every CPU.
+#[unstable(feature = "rinuxcore_multicore", issue = "none")]
+pub fn steals() -> u64 {
+    STEALS.total()
+}
+
+fn schedule(job: Arc<Job>) {
+    if job.queued.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    let home = job.home.load(Ordering::Relaxed);
+    QUEUES.get_for(home).expect("CPU index out of range").push(job);
+    // Pairs with the fence in `idle`: either the CPU sees the job before it
+    // halts, or we see it idle and wake it.
+    fence(Ordering::SeqCst);
+    let current = cpu_id();
+    if is_idle(home) {
+        if home != current {
+            smp::wake(home);
+        }
+    } else if let Some(thief) = (0..smp::cpu_count()).find(|&cpu| cpu != current && is_idle(cpu)) {
+        smp::wake(thief);
+    }
+}
+
+fn is_idle(cpu: usize) -> bool {
+    IDLE.get_for(cpu).map_or(false, |idle| idle.load(Ordering::SeqCst))
+}
+
+fn worker() -> ! {
+    loop {
+        deferred::run_pending();
+        run_ready();
+        idle();
+    }
+}
+
+/// Polls jobs until neither this CPU's queue nor any other has one left.
+/// Returns how many were polled.
+fn run_ready() -> usize {
+    let mut polled = 0;
+    while let Some(job) = QUEUES.get().pop().or_else(steal) {
+        poll(job);
+        polled += 1;
+    }
+    polled
+}
+
+fn steal() -> Option<Arc<Job>> {
+    let cpus = smp::cpu_count();
+    let current = cpu_id();
+    let job = (1..cpus)
+        .map(|offset| (current + offset) % cpus)
+        .find_map(|cpu| QUEUES.get_for(cpu).and_then(SegQueue::pop))?;
+    STEALS.get().increment();
+    Some(job)
+}
+
+fn poll(job: Arc<Job>) {
+    job.home.store(cpu_id(), Ordering::Relaxed);
+    // Cleared before polling, so a wake during the poll queues the job again.
+    job.queued.store(false, Ordering::Release);
+    let mut future = job.future.lock();
+    if let Some(pinned) = future.as_mut() {
+        let waker = Waker::from(job.clone());
+        let mut context = Context::from_waker(&waker);
+        set_current_task(Some(job.id));
+        let ready = pinned.as_mut().poll(&mut context).is_ready();
+        set_current_task(None);
+        POLLS.get().increment();
+        if ready {
+            *future = None;
+        }
+    }
+}
+
+fn idle() {
+    let flag = IDLE.get();
+    interrupts::disable();
+    flag.store(true, Ordering::SeqCst);
+    fence(Ordering::SeqCst);
+    let work = (0..smp::cpu_count()).any(|cpu| !QUEUES.get_for(cpu).unwrap().is_empty());
+    if work || deferred::pending() != 0 {
+        flag.store(false, Ordering::SeqCst);
+        interrupts::enable();
+    } else if cpu_id() == 0 && thread::others_ready() {
+        // Kernel threads only run on the bootstrap processor.
+        flag.store(false, Ordering::SeqCst);
+        interrupts::enable();
+        thread::yield_now();
+    } else {
+        interrupts::enable_and_hlt();
+        flag.store(false, Ordering::SeqCst);
+    }
+}
+
+#[test_case]
+fn test_multicore_spawn_and_poll() {
+    static DONE: AtomicBool = AtomicBool::new(false);
+    spawn(async {
+        DONE.store(true, Ordering::SeqCst);
+    });
+    while !DONE.load(Ordering::SeqCst) {
+        run_ready();
+    }
+    assert!(polls() >= 1);
+}
@@ -25,7 +25,7 @@
 //! Simple task executor, used for backwards compatibility
 //! use the `executor` module for a better task executor
 
-use super::Task;
+use super::{deferred, Task};
 use std3::collections::VecDeque;
 use std3::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 
@@ -59,6 +59,7 @@ impl SimpleExecutor {
     #[unstable(feature = "rinuxcore_task", issue = "none")]
     pub fn run(&mut self) {
         while let Some(mut task) = self.task_queue.pop_front() {
+            deferred::run_pending();
             let waker = dummy_waker();
             let mut context = Context::from_waker(&waker);
             match task.poll(&mut context) {
@@ -0,0 +1,459 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Preemptive kernel threads.
+//!
+//! Every thread has its own stack, with an unmapped guard page below it so
+//! an overflow faults instead of corrupting other memory. Switching saves the callee-saved
+//! registers on the old stack and restores them from the new one; the timer
+//! interrupt preempts whatever thread it lands on in ring 0. The thread that
+//! called `rinuxcore::init` becomes the first thread, so an `Executor` can
+//! keep running there while other work runs on threads:
+//!
+//! ```rust
+//! let worker = rinuxcore::thread::spawn_thread(|| (1..=10u64).product::<u64>());
+//! assert_eq!(worker.join(), 3_628_800);
+//! ```
+//!
+//! The scheduler's lock is only taken with interrupts disabled, and threads
+//! only switch with interrupts disabled, so the timer never preempts a
+//! thread in the middle of a switch.
+//!
+//! Threads only run on the bootstrap processor. Called on another CPU, for
+//! example from a [multi-core](crate::task::multicore) task, [`yield_now`],
+//! [`sleep`] and [`JoinHandle::join`] spin instead of switching.
+
+use crate::memory;
+use crate::per_cpu::{self, Counter};
+use crate::task;
+use crate::time::Instant;
+use crate::vga_buffer::print_ok;
+use std3::__reexports::x86_64;
+use std3::arch::global_asm;
+use std3::boxed::Box;
+use std3::collections::{BTreeMap, VecDeque};
+use std3::sync::atomic::{AtomicU64, Ordering};
+use std3::sync::{Arc, Mutex, MutexGuard};
+use std3::{fmt, time::Duration, vec::Vec};
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+/// Stack size of every spawned thread.
+#[unstable(feature = "rinuxcore_thread", issue = "none")]
+pub const STACK_SIZE: usize = 16 * 1024;
+
+/// Identifies a kernel thread.
+#[unstable(feature = "rinuxcore_thread", issue = "none")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> ThreadId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The raw number. The boot thread is 0.
+    #[unstable(feature = "rinuxcore_thread", issue = "none")]
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    Ready,
+    /// Until the given time, in nanoseconds since boot
+    Sleeping(u64),
+    /// Waiting for another thread to finish
+    Blocked,
+    Finished,
+}
+
+struct Thread {
+    /// Saved stack pointer while switched out
+    rsp: u64,
+    /// Top of the stack, `None` for the boot thread, which runs on the
+    /// bootloader's stack
+    stack: Option<VirtAddr>,
+    state: State,
+    joiners: Vec<ThreadId>,
+}
+
+struct Threads {
+    /// Boxed so `rsp` stays put while the lock is released for a switch.
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    ready: VecDeque<ThreadId>,
+    current: ThreadId,
+    idle: ThreadId,
+    /// Stacks of finished threads, reused by new ones since kernel stacks
+    /// are never unmapped.
+    free_stacks: Vec<VirtAddr>,
+}
+
+static THREADS: Mutex<Option<Threads>> = Mutex::new(None);
+
+crate::per_cpu! {
+    static SWITCHES: Counter = Counter::new();
+}
+
+extern "C" {
+    fn rinux_switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn rinux_thread_start();
+}
+
+global_asm!(
+    ".global rinux_switch_context",
+    "rinux_switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    "",
+    // First code a new thread runs; `r12` holds its boxed closure.
+    ".global rinux_thread_start",
+    "rinux_thread_start:",
+    "mov rdi, r12",
+    "and rsp, -16",
+    "sti",
+    "call rinux_thread_entry",
+    "ud2",
+);
+
+type Main = Box<dyn FnOnce() + Send>;
+
+#[no_mangle]
+extern "C" fn rinux_thread_entry(main: *mut Main) -> ! {
+    let main = unsafe { Box::from_raw(main) };
+    main();
+    interrupts::disable();
+    let mut threads = THREADS.lock();
+    let state = threads.as_mut().expect("threads not initialized");
+    let current = state.current;
+    let joiners = {
+        let thread = state.threads.get_mut(&current).unwrap();
+        std3::mem::take(&mut thread.joiners)
+    };
+    for joiner in joiners {
+        state.threads.get_mut(&joiner).unwrap().state = State::Ready;
+        state.ready.push_back(joiner);
+    }
+    reschedule(threads, State::Finished);
+    unreachable!("finished thread was scheduled again");
+}
+
+/// Registers the running code as the boot thread and starts the idle thread.
+pub(crate) fn init() {
+    let boot = Box::new(Thread {
+        rsp: 0,
+        stack: None,
+        state: State::Running,
+        joiners: Vec::new(),
+    });
+    let boot_id = ThreadId::new();
+    let idle_id = ThreadId::new();
+    let idle = new_thread(
+        alloc_stack(),
+        Box::new(|| loop {
+            interrupts::enable_and_hlt();
+            yield_now();
+        }),
+    );
+
+    let mut threads = BTreeMap::new();
+    threads.insert(boot_id, boot);
+    threads.insert(idle_id, idle);
+    interrupts::without_interrupts(|| {
+        *THREADS.lock() = Some(Threads {
+            threads,
+            ready: VecDeque::new(),
+            current: boot_id,
+            idle: idle_id,
+            free_stacks: Vec::new(),
+        });
+    });
+    unsafe {
+        if !crate::CONFIG.quiet_boot {
+            print_ok!("[OK] Kernel threads initialized\n");
+        }
+    }
+}
+
+fn alloc_stack() -> VirtAddr {
+    memory::alloc_stack(STACK_SIZE as u64).expect("failed to map a thread stack")
+}
+
+/// Prepares the stack at `top` so the first switch lands in
+/// `rinux_thread_start`.
+fn new_thread(top: VirtAddr, main: Main) -> Box<Thread> {
+    let top = top.align_down(16u64).as_u64();
+    let main = Box::into_raw(Box::new(main)) as u64;
+    // r15, r14, r13, r12, rbx, rbp, return address, padding
+    let frame = [0, 0, 0, main, 0, 0, rinux_thread_start as unsafe extern "C" fn() as u64, 0];
+    let rsp = top - (frame.len() * 8) as u64;
+    unsafe { (rsp as *mut [u64; 8]).write(frame) };
+    Box::new(Thread {
+        rsp,
+        stack: Some(VirtAddr::new(top)),
+        state: State::Ready,
+        joiners: Vec::new(),
+    })
+}
+
+/// Whether the running CPU is the one threads are scheduled on.
+fn schedulable() -> bool {
+    per_cpu::cpu_id() == 0
+}
+
+/// Moves the current thread to `state` and switches to the next ready
+/// thread, if there is one. Must be called with interrupts disabled; returns
+/// once the current thread runs again.
+fn reschedule(mut guard: MutexGuard<Option<Threads>>, state: State) {
+    assert!(schedulable(), "kernel threads only run on the bootstrap processor");
+    let threads = guard.as_mut().expect("threads not initialized");
+    let now = Instant::now().as_nanos();
+    for (&id, thread) in threads.threads.iter_mut() {
+        match thread.state {
+            State::Sleeping(deadline) if deadline <= now => {
+                thread.state = State::Ready;
+                threads.ready.push_back(id);
+            }
+            _ => {}
+        }
+    }
+    // Threads that finished earlier can go now that nobody runs on their
+    // stacks.
+    let current = threads.current;
+    let free_stacks = &mut threads.free_stacks;
+    threads.threads.retain(|&id, thread| {
+        let keep = id == current || thread.state != State::Finished;
+        if !keep {
+            free_stacks.extend(thread.stack);
+        }
+        keep
+    });
+
+    let runnable = state == State::Ready;
+    let next = match threads.ready.pop_front() {
+        Some(next) => next,
+        None if runnable => {
+            threads.threads.get_mut(&current).unwrap().state = State::Running;
+            return;
+        }
+        None => threads.idle,
+    };
+    if runnable && current != threads.idle {
+        threads.ready.push_back(current);
+    }
+    threads.threads.get_mut(&current).unwrap().state = state;
+    let old_rsp = &mut threads.threads.get_mut(&current).unwrap().rsp as *mut u64;
+    let next_thread = threads.threads.get_mut(&next).unwrap();
+    next_thread.state = State::Running;
+    let new_rsp = next_thread.rsp;
+    threads.current = next;
+    SWITCHES.get().increment();
+    drop(guard);
+    // New threads start with no task; others get theirs back below
+    let poll_context = task::take_poll_context();
+    unsafe { rinux_switch_context(old_rsp, new_rsp) };
+    task::restore_poll_context(poll_context);
+}
+
+/// Runs `main` on a new kernel thread.
+#[unstable(feature = "rinuxcore_thread", issue = "none")]
+pub fn spawn_thread<F, T>(main: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let slot = result.clone();
+    let stack = interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        threads.as_mut().expect("threads not initialized").free_stacks.pop()
+    });
+    let main: Main = Box::new(move || {
+        let value = main();
+        *slot.lock() = Some(value);
+    });
+    let thread = new_thread(stack.unwrap_or_else(alloc_stack), main);
+    let id = ThreadId::new();
+    interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let threads = threads.as_mut().expect("threads not initialized");
+        threads.threads.insert(id, thread);
+        threads.ready.push_back(id);
+    });
+    JoinHandle { id, result }
+}
+
+/// Lets other ready threads run.
+#[unstable(feature = "rinuxcore_thread", issue = "none")]
+pub fn yield_now() {
+    if !schedulable() {
+        std3::hint::spin_loop();
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let threads = THREADS.lock();
+        if threads.is_some() {
+            reschedule(threads, State::Ready);
+        }
+    });
+}
+
+/// Blocks the current thread for at least `duration`.
+#[unstable(feature = "rinuxcore_thread", issue = "none")]
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now().as_nanos().saturating_add(duration.as_nanos() as u64);
+    while Instant::now().as_nanos() < deadline {
+        if !schedulable() {
+            std3::hint::spin_loop();
+            continue;
+        }
+        interrupts::without_interrupts(|| {
+            let threads = THREADS.lock();
+            if threads.is_some() {
+                reschedule(threads, State::Sleeping(deadline));
+            }
+        });
+    }
+}
+
+/// The thread calling this.
+#[unstable(feature = "rinuxcore_thread", issue = "none")]
+pub fn current() -> ThreadId {
+    interrupts::without_interrupts(|| {
+        THREADS.lock().as_ref().map_or(ThreadId(0), |threads| threads.current)
+    })
+}
+
+/// Number of context switches between kernel threads since boot.
+#[unstable(feature = "rinuxcore_thread", issue = "none")]
+pub fn context_switches() -> u64 {
+    SWITCHES.total()
+}
+
+/// Whether some thread other than the current one is waiting for the CPU.
+pub(crate) fn others_ready() -> bool {
+    interrupts::without_interrupts(|| {
+        THREADS.lock().as_ref().map_or(false, |threads| !threads.ready.is_empty())
+    })
+}
+
+/// Called by the timer interrupt when it lands in ring 0, after the end of
+/// interrupt has been sent.
+pub(crate) fn preempt() {
+    if !schedulable() {
+        return;
+    }
+    if let Some(threads) = THREADS.try_lock() {
+        if threads.is_some() {
+            reschedule(threads, State::Ready);
+        }
+    }
+}
+
+/// Owned permission to wait for a thread, returned by [`spawn_thread`].
+#[unstable(feature = "rinuxcore_thread", issue = "none")]
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// The thread's identifier.
+    #[unstable(feature = "rinuxcore_thread", issue = "none")]
+    pub fn thread(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Whether the thread has returned.
+    #[unstable(feature = "rinuxcore_thread", issue = "none")]
+    pub fn is_finished(&self) -> bool {
+        self.result.lock().is_some()
+    }
+
+    /// Blocks until the thread returns, and gives back its result.
+    #[unstable(feature = "rinuxcore_thread", issue = "none")]
+    pub fn join(self) -> T {
+        loop {
+            if let Some(value) = self.result.lock().take() {
+                return value;
+            }
+            if !schedulable() {
+                std3::hint::spin_loop();
+                continue;
+            }
+            interrupts::without_interrupts(|| {
+                let mut guard = THREADS.lock();
+                let threads = guard.as_mut().expect("threads not initialized");
+                let current = threads.current;
+                let waiting = match threads.threads.get_mut(&self.id) {
+                    Some(thread) if thread.state != State::Finished => {
+                        thread.joiners.push(current);
+                        true
+                    }
+                    _ => false,
+                };
+                if waiting {
+                    reschedule(guard, State::Blocked);
+                }
+            });
+        }
+    }
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JoinHandle").field("thread", &self.id).finish()
+    }
+}
+
+#[test_case]
+fn test_threads_run_and_join() {
+    let worker = spawn_thread(|| {
+        yield_now();
+        (1..=10u64).product::<u64>()
+    });
+    assert_eq!(worker.join(), 3_628_800);
+}
@@ -0,0 +1,111 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! High Precision Event Timer, used as a calibration reference when present.
+
+use crate::{acpi, memory};
+use std3::__reexports::x86_64::{PhysAddr, VirtAddr};
+use std3::ptr;
+
+const GENERAL_CAPABILITIES: u64 = 0x000;
+const GENERAL_CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0F0;
+
+const ENABLE: u64 = 1;
+/// Set in the capabilities if the main counter is 64 bits wide, not 32.
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+
+/// Offset of the base address inside the ACPI `HPET` table.
+const BASE_ADDRESS_OFFSET: usize = 44;
+
+pub(super) struct Hpet {
+    base: VirtAddr,
+    /// Counter period in femtoseconds.
+    period: u64,
+    /// The bits the main counter actually has.
+    mask: u64,
+}
+
+impl Hpet {
+    /// Finds the HPET through ACPI and makes sure its main counter is running.
+    pub(super) fn probe() -> Option<Hpet> {
+        let table = acpi::find_table(b"HPET")?;
+        let bytes = table.bytes();
+        let mut address = [0u8; 8];
+        address.copy_from_slice(bytes.get(BASE_ADDRESS_OFFSET..BASE_ADDRESS_OFFSET + 8)?);
+        let address = u64::from_le_bytes(address);
+        if address == 0 {
+            return None;
+        }
+
+        let base = memory::map_mmio(PhysAddr::new(address), 0x400).ok()?;
+        let hpet = Hpet { base, period: 0, mask: 0 };
+        let capabilities = unsafe { hpet.read(GENERAL_CAPABILITIES) };
+        let period = capabilities >> 32;
+        // The specification caps the period at 100ns.
+        if period == 0 || period > 100_000_000 {
+            return None;
+        }
+
+        let mask = if capabilities & COUNT_SIZE_CAP != 0 {
+            u64::MAX
+        } else {
+            u32::MAX as u64
+        };
+        let hpet = Hpet { period, mask, ..hpet };
+        unsafe {
+            let config = hpet.read(GENERAL_CONFIGURATION);
+            hpet.write(GENERAL_CONFIGURATION, config | ENABLE);
+        }
+        Some(hpet)
+    }
+
+    unsafe fn read(&self, register: u64) -> u64 {
+        ptr::read_volatile((self.base + register).as_ptr())
+    }
+
+    unsafe fn write(&self, register: u64, value: u64) {
+        ptr::write_volatile((self.base + register).as_mut_ptr(), value)
+    }
+
+    /// Number of counter ticks in `micros` microseconds.
+    pub(super) fn ticks_in(&self, micros: u64) -> u64 {
+        micros * 1_000_000_000 / self.period
+    }
+
+    /// Converts a tick count back into femtoseconds.
+    pub(super) fn femtos(&self, ticks: u64) -> u128 {
+        ticks as u128 * self.period as u128
+    }
+
+    pub(super) fn counter(&self) -> u64 {
+        unsafe { self.read(MAIN_COUNTER) & self.mask }
+    }
+
+    /// Ticks from counter value `start` to `end`, across one wrap of a
+    /// 32-bit counter.
+    pub(super) fn ticks_between(&self, start: u64, end: u64) -> u64 {
+        end.wrapping_sub(start) & self.mask
+    }
+}
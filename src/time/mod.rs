@@ -0,0 +1,180 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Timekeeping
+//!
+//! The TSC is calibrated once during `rinuxcore::init` against the HPET when
+//! the firmware advertises one, or against PIT channel 2 otherwise. After that
+//! [`now`] gives nanosecond timestamps without touching any I/O port, which
+//! makes it cheap enough for logging, tracing and benchmarking.
+//!
+//! Wall-clock time comes from the CMOS RTC, read once at boot and advanced
+//! with the TSC afterwards, see [`SystemTime`].
+
+use crate::vga_buffer::print_ok;
+use std3::sync::atomic::{AtomicU64, Ordering};
+use std3::{fmt, ops::Sub, time::Duration};
+
+mod hpet;
+mod pit;
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+pub mod rtc;
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+pub mod tsc;
+
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+pub use rtc::DateTime;
+
+/// Unix time, in seconds, read from the RTC right after calibration.
+static BOOT_UNIX_SECONDS: AtomicU64 = AtomicU64::new(0);
+/// Monotonic timestamp taken together with `BOOT_UNIX_SECONDS`.
+static BOOT_INSTANT: AtomicU64 = AtomicU64::new(0);
+
+/// A monotonic timestamp, measured in nanoseconds since the TSC was calibrated.
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Returns the current timestamp.
+    #[unstable(feature = "rinuxcore_time", issue = "none")]
+    pub fn now() -> Instant {
+        Instant(tsc::ticks_to_nanos(tsc::read().saturating_sub(tsc::boot_ticks())))
+    }
+
+    /// Nanoseconds between calibration and this timestamp.
+    #[unstable(feature = "rinuxcore_time", issue = "none")]
+    pub const fn as_nanos(self) -> u64 {
+        self.0
+    }
+
+    /// Time elapsed since `earlier`, zero if `earlier` is actually later.
+    #[unstable(feature = "rinuxcore_time", issue = "none")]
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Time elapsed since this timestamp was taken.
+    #[unstable(feature = "rinuxcore_time", issue = "none")]
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+impl fmt::Debug for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:09}s", self.0 / 1_000_000_000, self.0 % 1_000_000_000)
+    }
+}
+
+/// Wall-clock time, measured since the Unix epoch.
+///
+/// The RTC only has a resolution of one second, so it is read once at boot
+/// and the monotonic clock is added on top. Time zones are not handled; the
+/// RTC is assumed to run in UTC.
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime(Duration);
+
+impl SystemTime {
+    /// 1970-01-01 00:00:00 UTC.
+    #[unstable(feature = "rinuxcore_time", issue = "none")]
+    pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::from_secs(0));
+
+    /// Returns the current wall-clock time.
+    #[unstable(feature = "rinuxcore_time", issue = "none")]
+    pub fn now() -> SystemTime {
+        let boot = Duration::from_secs(BOOT_UNIX_SECONDS.load(Ordering::Relaxed));
+        let booted = Instant(BOOT_INSTANT.load(Ordering::Relaxed));
+        let since_boot = Instant::now().duration_since(booted);
+        SystemTime(boot + since_boot)
+    }
+
+    /// Time elapsed since `earlier`, or `None` if `earlier` is later than `self`.
+    #[unstable(feature = "rinuxcore_time", issue = "none")]
+    pub fn duration_since(&self, earlier: SystemTime) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    /// The calendar date and time, truncated to whole seconds.
+    #[unstable(feature = "rinuxcore_time", issue = "none")]
+    pub fn to_datetime(&self) -> DateTime {
+        DateTime::from_unix_timestamp(self.0.as_secs())
+    }
+}
+
+impl fmt::Display for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_datetime())
+    }
+}
+
+/// Returns the current timestamp, see [`Instant::now`].
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+pub fn now() -> Instant {
+    Instant::now()
+}
+
+/// Busy-waits for at least `duration`.
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+pub fn spin_wait(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        std3::hint::spin_loop();
+    }
+}
+
+pub(crate) fn init() {
+    let source = tsc::calibrate();
+    rtc::init();
+    let date = rtc::read();
+    BOOT_INSTANT.store(Instant::now().as_nanos(), Ordering::Relaxed);
+    BOOT_UNIX_SECONDS.store(date.to_unix_timestamp(), Ordering::Relaxed);
+    unsafe {
+        if !crate::CONFIG.quiet_boot {
+            print_ok!(
+                "[OK] TSC calibrated against {}: {} kHz{}\n",
+                source,
+                tsc::frequency() / 1000,
+                if tsc::is_invariant() { " (invariant)" } else { "" }
+            );
+            print_ok!("[OK] RTC initialized: {}\n", date);
+        }
+    }
+}
+
+#[test_case]
+fn test_instant_is_monotonic() {
+    let first = Instant::now();
+    let second = Instant::now();
+    assert!(second >= first);
+}
@@ -0,0 +1,72 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! PIT channel 2, used as a one-shot reference while calibrating the TSC.
+
+use std3::__reexports::x86_64::instructions::port::Port;
+
+/// Input clock of the 8253/8254, in Hz.
+pub(super) const FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Keyboard controller port B, which gates channel 2 and reports its output.
+const PORT_B: u16 = 0x61;
+
+const GATE: u8 = 0x01;
+const SPEAKER: u8 = 0x02;
+const OUTPUT: u8 = 0x20;
+
+/// Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count).
+const ONE_SHOT: u8 = 0b1011_0000;
+
+/// Programs channel 2 to count down `micros`, calls `start`, waits for the
+/// terminal count and calls `end`.
+///
+/// Must run with interrupts disabled so nothing stretches the measurement.
+pub(super) unsafe fn measure<S, E>(micros: u64, start: S, end: E)
+where
+    S: FnOnce(),
+    E: FnOnce(),
+{
+    let mut port_b: Port<u8> = Port::new(PORT_B);
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel_2: Port<u8> = Port::new(CHANNEL_2);
+
+    let count = (FREQUENCY * micros / 1_000_000).min(u16::MAX as u64) as u16;
+
+    let control = port_b.read() & !(SPEAKER | GATE);
+    port_b.write(control);
+    command.write(ONE_SHOT);
+    channel_2.write(count as u8);
+    channel_2.write((count >> 8) as u8);
+
+    // The count only starts on the rising edge of the gate.
+    port_b.write(control | GATE);
+    start();
+    while port_b.read() & OUTPUT == 0 {}
+    end();
+
+    port_b.write(control);
+}
@@ -0,0 +1,300 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! CMOS real-time clock
+//!
+//! Reads the calendar time kept by the MC146818-compatible RTC, handling BCD
+//! and binary encodings, 12 and 24 hour modes and the ACPI century register,
+//! and drives the optional periodic interrupt on IRQ8.
+
+use crate::acpi;
+use std3::__reexports::x86_64::instructions::{interrupts, port::Port};
+use std3::fmt;
+use std3::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+const REG_STATUS_C: u8 = 0x0C;
+
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+const PERIODIC_INTERRUPT_ENABLE: u8 = 0x40;
+const BINARY_MODE: u8 = 0x04;
+const HOUR_24: u8 = 0x02;
+const HOUR_PM: u8 = 0x80;
+
+/// Offset of the century register index inside the ACPI FADT.
+const FADT_CENTURY_OFFSET: usize = 108;
+
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// A calendar date and time as kept by the RTC, usually UTC.
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    /// Full year, e.g. `2022`.
+    pub year: u16,
+    /// Month, `1..=12`.
+    pub month: u8,
+    /// Day of the month, `1..=31`.
+    pub day: u8,
+    /// Hour, `0..=23`.
+    pub hour: u8,
+    /// Minute, `0..=59`.
+    pub minute: u8,
+    /// Second, `0..=59`.
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00.
+    #[unstable(feature = "rinuxcore_time", issue = "none")]
+    pub fn to_unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        days as u64 * 86_400
+            + self.hour as u64 * 3_600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    /// Builds a date from seconds since 1970-01-01 00:00:00.
+    #[unstable(feature = "rinuxcore_time", issue = "none")]
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let (year, month, day) = civil_from_days((timestamp / 86_400) as i64);
+        let seconds = timestamp % 86_400;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3_600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Days since the epoch for a proleptic Gregorian date (Howard Hinnant's algorithm).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = (if days >= 0 { days } else { days - 146_096 }) / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+unsafe fn read_register(register: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+    address.write(register);
+    data.read()
+}
+
+unsafe fn write_register(register: u8, value: u8) {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+    address.write(register);
+    data.write(value);
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+unsafe fn read_raw() -> RawTime {
+    while read_register(REG_STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+    let century_register = CENTURY_REGISTER.load(Ordering::Relaxed);
+    RawTime {
+        second: read_register(REG_SECONDS),
+        minute: read_register(REG_MINUTES),
+        hour: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+        century: match century_register {
+            0 => 0,
+            register => read_register(register),
+        },
+    }
+}
+
+/// Reads the current date and time from the RTC.
+///
+/// Registers are read until two consecutive snapshots agree, so an update
+/// happening halfway through cannot produce a torn value.
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+pub fn read() -> DateTime {
+    let (raw, status_b) = interrupts::without_interrupts(|| unsafe {
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, read_register(REG_STATUS_B))
+    });
+
+    let binary = status_b & BINARY_MODE != 0;
+    let decode = |value: u8| if binary { value } else { bcd_to_binary(value) };
+
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut hour = decode(raw.hour & !HOUR_PM);
+    if status_b & HOUR_24 == 0 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let year = decode(raw.year) as u16;
+    let year = match raw.century {
+        0 if year < 70 => 2000 + year,
+        0 => 1900 + year,
+        century => decode(century) as u16 * 100 + year,
+    };
+
+    DateTime {
+        year,
+        month: decode(raw.month),
+        day: decode(raw.day),
+        hour,
+        minute: decode(raw.minute),
+        second: decode(raw.second),
+    }
+}
+
+/// Enables the periodic interrupt on IRQ8 at `32768 >> (rate - 1)` Hz.
+///
+/// `rate` is clamped to `3..=15`, i.e. 8192 Hz down to 2 Hz. The interrupt is
+/// acknowledged by the handler in `interrupts`; [`periodic_ticks`] counts it.
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+pub fn enable_periodic_interrupt(rate: u8) {
+    let rate = rate.max(3).min(15);
+    interrupts::without_interrupts(|| unsafe {
+        let status_a = read_register(REG_STATUS_A);
+        write_register(REG_STATUS_A, (status_a & 0xF0) | rate);
+        let status_b = read_register(REG_STATUS_B);
+        write_register(REG_STATUS_B, status_b | PERIODIC_INTERRUPT_ENABLE);
+        // Throw away anything that was pending so the next IRQ is a fresh one.
+        read_register(REG_STATUS_C);
+        crate::interrupts::enable_rtc_irq();
+    });
+}
+
+/// Stops the periodic interrupt.
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+pub fn disable_periodic_interrupt() {
+    interrupts::without_interrupts(|| unsafe {
+        let status_b = read_register(REG_STATUS_B);
+        write_register(REG_STATUS_B, status_b & !PERIODIC_INTERRUPT_ENABLE);
+    });
+}
+
+/// Number of periodic interrupts handled so far.
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// Called from the IRQ8 handler. Reading status register C acknowledges the
+/// interrupt; the RTC raises no further IRQs until it is read.
+pub(crate) fn handle_interrupt() {
+    unsafe { read_register(REG_STATUS_C) };
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(super) fn init() {
+    let century_register = acpi::find_table(b"FACP")
+        .and_then(|fadt| fadt.bytes().get(FADT_CENTURY_OFFSET).copied())
+        .unwrap_or(0);
+    CENTURY_REGISTER.store(century_register, Ordering::Relaxed);
+}
+
+#[test_case]
+fn test_unix_timestamp_round_trip() {
+    let date = DateTime {
+        year: 2022,
+        month: 2,
+        day: 28,
+        hour: 23,
+        minute: 59,
+        second: 30,
+    };
+    assert_eq!(date.to_unix_timestamp(), 1_646_092_770);
+    assert_eq!(DateTime::from_unix_timestamp(1_646_092_770), date);
+    assert_eq!(DateTime::from_unix_timestamp(0).year, 1970);
+}
+
+#[test_case]
+fn test_bcd_to_binary() {
+    assert_eq!(bcd_to_binary(0x59), 59);
+    assert_eq!(bcd_to_binary(0x12), 12);
+}
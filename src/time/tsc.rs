@@ -0,0 +1,142 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Time Stamp Counter access and calibration
+
+use super::{hpet::Hpet, pit};
+use std3::__reexports::x86_64::instructions::interrupts;
+use std3::arch::x86_64::{__cpuid, _rdtsc};
+use std3::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Length of a single calibration window, in microseconds.
+const CALIBRATION_MICROS: u64 = 10_000;
+/// Calibration windows measured; the shortest one wins.
+const CALIBRATION_ROUNDS: usize = 3;
+
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static BOOT_TICKS: AtomicU64 = AtomicU64::new(0);
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+
+/// Reference timer the TSC was calibrated against.
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationSource {
+    /// The High Precision Event Timer, found through ACPI.
+    Hpet,
+    /// Channel 2 of the legacy Programmable Interval Timer.
+    Pit,
+}
+
+impl std3::fmt::Display for CalibrationSource {
+    fn fmt(&self, f: &mut std3::fmt::Formatter) -> std3::fmt::Result {
+        match self {
+            CalibrationSource::Hpet => write!(f, "HPET"),
+            CalibrationSource::Pit => write!(f, "PIT"),
+        }
+    }
+}
+
+/// Reads the raw TSC.
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+#[inline]
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Calibrated TSC frequency in Hz, zero before calibration.
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Whether CPUID reports an invariant TSC, one that ticks at a constant rate
+/// regardless of power states and frequency changes.
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+pub fn is_invariant() -> bool {
+    INVARIANT.load(Ordering::Relaxed)
+}
+
+/// Converts a TSC delta to nanoseconds, zero before calibration.
+#[unstable(feature = "rinuxcore_time", issue = "none")]
+pub fn ticks_to_nanos(ticks: u64) -> u64 {
+    match frequency() {
+        0 => 0,
+        frequency => (ticks as u128 * 1_000_000_000 / frequency as u128) as u64,
+    }
+}
+
+pub(super) fn boot_ticks() -> u64 {
+    BOOT_TICKS.load(Ordering::Relaxed)
+}
+
+fn detect_invariant() -> bool {
+    unsafe {
+        let max_extended = __cpuid(0x8000_0000).eax;
+        max_extended >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+    }
+}
+
+fn calibrate_with_hpet(hpet: &Hpet) -> u64 {
+    let window = hpet.ticks_in(CALIBRATION_MICROS);
+    let start_counter = hpet.counter();
+    let start = read();
+    let mut end_counter = hpet.counter();
+    while hpet.ticks_between(start_counter, end_counter) < window {
+        end_counter = hpet.counter();
+    }
+    let end = read();
+
+    let femtos = hpet.femtos(hpet.ticks_between(start_counter, end_counter));
+    ((end - start) as u128 * 1_000_000_000_000_000 / femtos) as u64
+}
+
+fn calibrate_with_pit() -> u64 {
+    let mut start = 0;
+    let mut end = 0;
+    unsafe { pit::measure(CALIBRATION_MICROS, || start = read(), || end = read()) };
+    (end - start) * (1_000_000 / CALIBRATION_MICROS)
+}
+
+pub(super) fn calibrate() -> CalibrationSource {
+    INVARIANT.store(detect_invariant(), Ordering::Relaxed);
+
+    let (source, frequency) = interrupts::without_interrupts(|| {
+        let hpet = Hpet::probe();
+        let rounds = (0..CALIBRATION_ROUNDS).map(|_| match &hpet {
+            Some(hpet) => calibrate_with_hpet(hpet),
+            None => calibrate_with_pit(),
+        });
+        // Anything that interferes with a window only ever makes it look longer.
+        let frequency = rounds.min().unwrap_or(0);
+        let source = match hpet {
+            Some(_) => CalibrationSource::Hpet,
+            None => CalibrationSource::Pit,
+        };
+        (source, frequency)
+    });
+
+    BOOT_TICKS.store(read(), Ordering::Relaxed);
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    source
+}
@@ -0,0 +1,282 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Per-program page tables.
+//!
+//! A new address space shares every top-level entry the kernel uses, so the
+//! kernel stays mapped while a program runs. Programs may only map memory in
+//! the remaining, empty 512 GiB slots; anything else would modify page tables
+//! the kernel shares with every other address space.
+
+use crate::memory::{phys_to_virt, physical_memory_offset, FRAME_ALLOCATOR};
+use std3::__reexports::x86_64;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{
+    mapper::{MapToError, TranslateResult},
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+    Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Why a mapping could not be made.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// Out of physical memory, or called before `rinuxcore::init`
+    FrameAllocationFailed,
+    /// The range overlaps a top-level slot the kernel uses
+    KernelRange,
+    /// The range is not mapped in this address space
+    NotMapped,
+    /// A huge page already covers part of the range
+    HugePage,
+    /// A page in the range already has a frame
+    AlreadyMapped,
+}
+
+impl From<MapToError<Size4KiB>> for MapError {
+    fn from(error: MapToError<Size4KiB>) -> MapError {
+        match error {
+            MapToError::FrameAllocationFailed => MapError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => MapError::HugePage,
+            MapToError::PageAlreadyMapped(_) => MapError::AlreadyMapped,
+        }
+    }
+}
+
+/// A level 4 page table with the kernel mapped in its upper part.
+///
+/// Dropping it returns the program's frames and page tables to the frame
+/// allocator. It must not be active at that point.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    kernel_slots: [u64; 8],
+}
+
+impl AddressSpace {
+    /// Creates an address space containing only the kernel's mappings.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn new() -> Result<AddressSpace, MapError> {
+        let level_4_frame = allocate_zeroed_frame()?;
+        let (active, _) = Cr3::read();
+        let kernel = unsafe { &*phys_to_virt(active.start_address()).as_ptr::<PageTable>() };
+        let table = phys_to_virt(level_4_frame.start_address()).as_mut_ptr::<PageTable>();
+        let table = unsafe { &mut *table };
+
+        let mut kernel_slots = [0u64; 8];
+        for (index, entry) in kernel.iter().enumerate() {
+            if entry.flags().contains(PageTableFlags::PRESENT) {
+                table[index] = entry.clone();
+                kernel_slots[index / 64] |= 1 << (index % 64);
+            }
+        }
+        Ok(AddressSpace {
+            level_4_frame,
+            kernel_slots,
+        })
+    }
+
+    /// Physical frame of the level 4 table, as loaded into CR3.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    fn is_kernel_slot(&self, address: VirtAddr) -> bool {
+        let index = usize::from(address.p4_index());
+        self.kernel_slots[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn mapper(&self) -> OffsetPageTable<'_> {
+        let table = phys_to_virt(self.level_4_frame.start_address()).as_mut_ptr::<PageTable>();
+        unsafe { OffsetPageTable::new(&mut *table, physical_memory_offset()) }
+    }
+
+    /// Maps `[start, start + size)` with fresh zeroed frames, user accessible
+    /// and with `flags`. Pages that are already mapped keep their frame and
+    /// gain `flags`.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn map_user(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        if size == 0 {
+            return Ok(());
+        }
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::<Size4KiB>::containing_address(start + (size - 1));
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+        let mut mapper = self.mapper();
+        for page in Page::range_inclusive(first, last) {
+            if self.is_kernel_slot(page.start_address()) {
+                return Err(MapError::KernelRange);
+            }
+            if let TranslateResult::Mapped { flags: existing, .. } =
+                mapper.translate(page.start_address())
+            {
+                // Two segments sharing a page: W^X cannot be kept, so the
+                // page gets both sets of permissions.
+                let no_execute = existing & flags & PageTableFlags::NO_EXECUTE;
+                let merged = ((existing | flags) - PageTableFlags::NO_EXECUTE) | no_execute;
+                unsafe {
+                    mapper
+                        .update_flags(page, merged)
+                        .map_err(|_| MapError::NotMapped)?
+                        .flush()
+                };
+                continue;
+            }
+            let frame = allocate_zeroed_frame()?;
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            let frame_allocator = frame_allocator.as_mut().ok_or(MapError::FrameAllocationFailed)?;
+            unsafe {
+                mapper
+                    .map_to_with_table_flags(page, frame, flags, table_flags, frame_allocator)?
+                    .flush()
+            };
+        }
+        Ok(())
+    }
+
+    /// Copies `bytes` to `address`, which must already be mapped. Works
+    /// whether or not this address space is active.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn write(&self, address: VirtAddr, bytes: &[u8]) -> Result<(), MapError> {
+        let mapper = self.mapper();
+        let mut written = 0;
+        while written < bytes.len() {
+            let target = address + written;
+            let physical = mapper.translate_addr(target).ok_or(MapError::NotMapped)?;
+            let page_left = 4096 - (target.as_u64() % 4096) as usize;
+            let chunk = page_left.min(bytes.len() - written);
+            unsafe {
+                phys_to_virt(physical)
+                    .as_mut_ptr::<u8>()
+                    .copy_from_nonoverlapping(bytes[written..].as_ptr(), chunk);
+            }
+            written += chunk;
+        }
+        Ok(())
+    }
+
+    /// Loads this address space into CR3 and returns the previous level 4
+    /// frame, for `switch_back`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must not touch memory that is only mapped in the previous
+    /// address space until it switches back.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub unsafe fn activate(&self) -> PhysFrame {
+        let (previous, flags) = Cr3::read();
+        if previous != self.level_4_frame {
+            Cr3::write(self.level_4_frame, flags);
+        }
+        previous
+    }
+
+    /// Loads `frame`, returned by [`activate`](Self::activate), back into CR3.
+    ///
+    /// # Safety
+    ///
+    /// `frame` must be a valid level 4 table that maps the kernel.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub unsafe fn switch_back(frame: PhysFrame) {
+        let (current, flags) = Cr3::read();
+        if current != frame {
+            Cr3::write(frame, flags);
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert_ne!(
+            Cr3::read().0,
+            self.level_4_frame,
+            "dropped the active address space"
+        );
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = match frame_allocator.as_mut() {
+            Some(frame_allocator) => frame_allocator,
+            None => return,
+        };
+        // Every mapping outside the kernel's slots was made by `map_user`
+        // with its own 4 KiB frame, so the whole tree below them is ours.
+        let level_4 = table(self.level_4_frame);
+        for (index, entry) in level_4.iter().enumerate() {
+            let kernel = self.kernel_slots[index / 64] & (1 << (index % 64)) != 0;
+            if !kernel && entry.flags().contains(PageTableFlags::PRESENT) {
+                unsafe { free_table(entry.addr(), 3, frame_allocator) };
+            }
+        }
+        unsafe { frame_allocator.deallocate_frame(self.level_4_frame) };
+    }
+}
+
+fn table(frame: PhysFrame) -> &'static PageTable {
+    unsafe { &*phys_to_virt(frame.start_address()).as_ptr::<PageTable>() }
+}
+
+/// Frees the page table at `address` at `level` (1 being the last), the
+/// tables below it and the frames they map.
+///
+/// # Safety
+///
+/// Nothing else may use the table or anything it maps.
+unsafe fn free_table(
+    address: PhysAddr,
+    level: u8,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let frame = PhysFrame::containing_address(address);
+    for entry in table(frame).iter() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        if level == 1 {
+            frame_allocator.deallocate_frame(PhysFrame::containing_address(entry.addr()));
+        } else {
+            free_table(entry.addr(), level - 1, frame_allocator);
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+}
+
+fn allocate_zeroed_frame() -> Result<PhysFrame, MapError> {
+    let frame = FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .and_then(|frame_allocator| frame_allocator.allocate_frame())
+        .ok_or(MapError::FrameAllocationFailed)?;
+    unsafe { phys_to_virt(frame.start_address()).as_mut_ptr::<u8>().write_bytes(0, 4096) };
+    Ok(frame)
+}
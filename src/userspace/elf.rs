@@ -0,0 +1,270 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Just enough ELF64 to load statically linked x86_64 executables.
+
+use std3::convert::TryInto;
+
+/// Program header type of a loadable segment
+#[unstable(feature = "rinuxcore_elf", issue = "none")]
+pub const PT_LOAD: u32 = 1;
+/// Segment flag: executable
+#[unstable(feature = "rinuxcore_elf", issue = "none")]
+pub const PF_X: u32 = 1;
+/// Segment flag: writable
+#[unstable(feature = "rinuxcore_elf", issue = "none")]
+pub const PF_W: u32 = 2;
+/// Segment flag: readable
+#[unstable(feature = "rinuxcore_elf", issue = "none")]
+pub const PF_R: u32 = 4;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3E;
+/// Segments must end below the canonical address hole.
+const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// Why an image was rejected.
+#[unstable(feature = "rinuxcore_elf", issue = "none")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// Shorter than the ELF header
+    TooShort,
+    /// Does not start with `\x7fELF`
+    BadMagic,
+    /// Not a 64-bit, little endian, version 1 file
+    UnsupportedFormat,
+    /// Not an `ET_EXEC` file for x86_64
+    NotExecutable,
+    /// The program header table is truncated or has the wrong entry size
+    BadProgramHeaders,
+    /// A `PT_LOAD` segment points outside the file or into kernel space
+    BadSegment,
+    /// The entry point is not inside an executable `PT_LOAD` segment
+    BadEntry,
+}
+
+/// A validated ELF64 executable.
+#[unstable(feature = "rinuxcore_elf", issue = "none")]
+#[derive(Debug, Clone, Copy)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u64,
+    program_headers: usize,
+    program_header_count: usize,
+}
+
+/// One program header.
+#[unstable(feature = "rinuxcore_elf", issue = "none")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    /// `p_type`
+    pub kind: u32,
+    /// `p_flags`
+    pub flags: u32,
+    /// `p_offset`
+    pub offset: u64,
+    /// `p_vaddr`
+    pub vaddr: u64,
+    /// `p_filesz`
+    pub file_size: u64,
+    /// `p_memsz`
+    pub memory_size: u64,
+}
+
+impl Segment {
+    /// Is this a `PT_LOAD` segment?
+    #[unstable(feature = "rinuxcore_elf", issue = "none")]
+    pub fn is_load(&self) -> bool {
+        self.kind == PT_LOAD
+    }
+
+    /// Is `PF_W` set?
+    #[unstable(feature = "rinuxcore_elf", issue = "none")]
+    pub fn is_writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    /// Is `PF_X` set?
+    #[unstable(feature = "rinuxcore_elf", issue = "none")]
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+impl<'a> Elf<'a> {
+    /// Validates the header, the program header table, every `PT_LOAD`
+    /// segment of `data` and the entry point.
+    #[unstable(feature = "rinuxcore_elf", issue = "none")]
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if data[..4] != *b"\x7fELF" {
+            return Err(ElfError::BadMagic);
+        }
+        // EI_CLASS, EI_DATA, EI_VERSION and e_version
+        if data[4] != 2 || data[5] != 1 || data[6] != 1 || u32_at(data, 20) != 1 {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        if u16_at(data, 16) != ET_EXEC || u16_at(data, 18) != EM_X86_64 {
+            return Err(ElfError::NotExecutable);
+        }
+
+        let program_headers = u64_at(data, 32);
+        let entry_size = usize::from(u16_at(data, 54));
+        let count = usize::from(u16_at(data, 56));
+        let table_end = (count * PROGRAM_HEADER_SIZE) as u64;
+        let in_bounds = program_headers
+            .checked_add(table_end)
+            .map_or(false, |end| end <= data.len() as u64);
+        if (count > 0 && entry_size != PROGRAM_HEADER_SIZE) || !in_bounds {
+            return Err(ElfError::BadProgramHeaders);
+        }
+
+        let elf = Elf {
+            data,
+            entry: u64_at(data, 24),
+            program_headers: program_headers as usize,
+            program_header_count: count,
+        };
+        for segment in elf.segments().filter(Segment::is_load) {
+            let file_end = segment.offset.checked_add(segment.file_size);
+            let memory_end = segment.vaddr.checked_add(segment.memory_size);
+            let valid = segment.file_size <= segment.memory_size
+                && file_end.map_or(false, |end| end <= data.len() as u64)
+                && memory_end.map_or(false, |end| end <= USER_END);
+            if !valid {
+                return Err(ElfError::BadSegment);
+            }
+        }
+        let entry_is_code = elf.segments().any(|segment| {
+            segment.is_load()
+                && segment.is_executable()
+                && (segment.vaddr..segment.vaddr + segment.memory_size).contains(&elf.entry)
+        });
+        if !entry_is_code {
+            return Err(ElfError::BadEntry);
+        }
+        Ok(elf)
+    }
+
+    /// Entry point address.
+    #[unstable(feature = "rinuxcore_elf", issue = "none")]
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// All program headers, in file order.
+    #[unstable(feature = "rinuxcore_elf", issue = "none")]
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + 'a {
+        let data = self.data;
+        let start = self.program_headers;
+        (0..self.program_header_count).map(move |index| {
+            let header = start + index * PROGRAM_HEADER_SIZE;
+            Segment {
+                kind: u32_at(data, header),
+                flags: u32_at(data, header + 4),
+                offset: u64_at(data, header + 8),
+                vaddr: u64_at(data, header + 16),
+                file_size: u64_at(data, header + 32),
+                memory_size: u64_at(data, header + 40),
+            }
+        })
+    }
+
+    /// The bytes of `segment` stored in the file.
+    #[unstable(feature = "rinuxcore_elf", issue = "none")]
+    pub fn segment_data(&self, segment: &Segment) -> &'a [u8] {
+        let start = segment.offset as usize;
+        &self.data[start..start + segment.file_size as usize]
+    }
+}
+
+/// An executable with `code` as its only segment, loaded at `vaddr`.
+#[cfg(test)]
+pub(super) fn test_image(vaddr: u64, entry: u64, flags: u32, code: &[u8]) -> std3::vec::Vec<u8> {
+    let mut image = std3::vec![0u8; HEADER_SIZE + PROGRAM_HEADER_SIZE];
+    image[..4].copy_from_slice(b"\x7fELF");
+    image[4] = 2;
+    image[5] = 1;
+    image[6] = 1;
+    image[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+    image[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+    image[20..24].copy_from_slice(&1u32.to_le_bytes());
+    image[24..32].copy_from_slice(&entry.to_le_bytes());
+    image[32..40].copy_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    image[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    image[56..58].copy_from_slice(&1u16.to_le_bytes());
+
+    let header = &mut image[HEADER_SIZE..];
+    header[..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+    header[4..8].copy_from_slice(&flags.to_le_bytes());
+    header[8..16].copy_from_slice(&((HEADER_SIZE + PROGRAM_HEADER_SIZE) as u64).to_le_bytes());
+    header[16..24].copy_from_slice(&vaddr.to_le_bytes());
+    header[32..40].copy_from_slice(&(code.len() as u64).to_le_bytes());
+    header[40..48].copy_from_slice(&(code.len() as u64).to_le_bytes());
+    image.extend_from_slice(code);
+    image
+}
+
+#[test_case]
+fn test_elf_header_validation() {
+    const BASE: u64 = 0x0000_1000_0000_0000;
+    let header = test_image(BASE, BASE + 4, PF_R | PF_X, &[0x90; 16]);
+
+    assert_eq!(Elf::parse(&header).map(|elf| elf.entry()), Ok(BASE + 4));
+    assert_eq!(Elf::parse(&header[..32]).err(), Some(ElfError::TooShort));
+
+    let mut bad = header.clone();
+    bad[0] = 0;
+    assert_eq!(Elf::parse(&bad).err(), Some(ElfError::BadMagic));
+    let mut bad = header.clone();
+    bad[4] = 1;
+    assert_eq!(Elf::parse(&bad).err(), Some(ElfError::UnsupportedFormat));
+    let mut bad = header.clone();
+    bad[18] = 0x28;
+    assert_eq!(Elf::parse(&bad).err(), Some(ElfError::NotExecutable));
+    let mut bad = header.clone();
+    bad[56] = 3;
+    assert_eq!(Elf::parse(&bad).err(), Some(ElfError::BadProgramHeaders));
+
+    let outside = test_image(BASE, BASE + 16, PF_R | PF_X, &[0x90; 16]);
+    assert_eq!(Elf::parse(&outside).err(), Some(ElfError::BadEntry));
+    let data = test_image(BASE, BASE, PF_R | PF_W, &[0x90; 16]);
+    assert_eq!(Elf::parse(&data).err(), Some(ElfError::BadEntry));
+}
@@ -0,0 +1,131 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Per-process handle tables.
+//!
+//! A handle is a number a program uses to refer to a kernel object, like a
+//! Unix file descriptor. Kernels add their own objects by implementing
+//! [`Handle`].
+
+use super::syscall::EBADF;
+use crate::{print, print_err};
+use std3::{fmt, str, sync::Arc, vec::Vec};
+
+/// A kernel object a program can refer to by handle number.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub trait Handle: fmt::Debug + Send + Sync {
+    /// Handles the `write` system call. Returns the number of bytes written
+    /// or a negative error.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    fn write(&self, _bytes: &[u8]) -> i64 {
+        EBADF
+    }
+}
+
+/// The VGA console, as handles 1 and 2 of every new process.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    /// Normal output
+    Output,
+    /// Error output, printed in red
+    Error,
+}
+
+impl Handle for Console {
+    fn write(&self, bytes: &[u8]) -> i64 {
+        let text = match str::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(_) => return super::syscall::EINVAL,
+        };
+        match self {
+            Console::Output => print!("{}", text),
+            Console::Error => print_err!("{}", text),
+        }
+        bytes.len() as i64
+    }
+}
+
+/// The handles a process has open, indexed by handle number.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+#[derive(Debug, Default)]
+pub struct HandleTable {
+    handles: Vec<Option<Arc<dyn Handle>>>,
+}
+
+impl HandleTable {
+    /// An empty table.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn new() -> HandleTable {
+        HandleTable::default()
+    }
+
+    /// A table with the console as handles 1 and 2. Handle 0, standard
+    /// input, is left closed.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn with_console() -> HandleTable {
+        let mut handles: Vec<Option<Arc<dyn Handle>>> = Vec::new();
+        handles.push(None);
+        handles.push(Some(Arc::new(Console::Output)));
+        handles.push(Some(Arc::new(Console::Error)));
+        HandleTable { handles }
+    }
+
+    /// Adds `handle` under the lowest free number and returns it.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn insert(&mut self, handle: Arc<dyn Handle>) -> u64 {
+        match self.handles.iter().position(Option::is_none) {
+            Some(index) => {
+                self.handles[index] = Some(handle);
+                index as u64
+            }
+            None => {
+                self.handles.push(Some(handle));
+                self.handles.len() as u64 - 1
+            }
+        }
+    }
+
+    /// The handle numbered `number`, if open.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn get(&self, number: u64) -> Option<Arc<dyn Handle>> {
+        self.handles.get(number as usize)?.clone()
+    }
+
+    /// Closes `number`, returning the handle if it was open.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn close(&mut self, number: u64) -> Option<Arc<dyn Handle>> {
+        self.handles.get_mut(number as usize)?.take()
+    }
+}
+
+#[test_case]
+fn test_handle_numbers_are_reused() {
+    let mut table = HandleTable::with_console();
+    assert!(table.get(0).is_none());
+    assert_eq!(table.insert(Arc::new(Console::Output)), 0);
+    assert_eq!(table.insert(Arc::new(Console::Output)), 3);
+    assert!(table.close(1).is_some());
+    assert_eq!(table.insert(Arc::new(Console::Error)), 1);
+}
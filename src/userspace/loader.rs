@@ -0,0 +1,234 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Loading ELF executables into their own address space.
+//!
+//! ```rust
+//! static INIT: &[u8] = include_bytes!("../user/init");
+//!
+//! let mut program = rinuxcore::userspace::loader::load(INIT, &["init"], &[]).unwrap();
+//! let exit = program.run();
+//! ```
+//!
+//! Programs must be linked above the kernel's low mappings, at [`USER_BASE`]
+//! or above; see [`AddressSpace`].
+
+use super::address_space::{AddressSpace, MapError};
+use super::elf::{Elf, ElfError};
+use super::{UserContext, UserExit};
+use std3::__reexports::x86_64;
+use std3::{mem, slice, vec::Vec};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Lowest address a program can be linked at. Programs only get the 512 GiB
+/// slots of the address space the kernel leaves empty, which
+/// [`AddressSpace`] finds at run time. The kernel image takes the first
+/// one, so the usual `0x400000` fails, while this address is free with the
+/// bootloader's layout.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub const USER_BASE: u64 = 0x0000_1000_0000_0000;
+/// Initial stack pointer of a new program; the stack grows down from here.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub const USER_STACK_TOP: u64 = 0x0000_7FFF_FFFF_F000;
+/// Size of the user stack.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub const USER_STACK_SIZE: u64 = 64 * 1024;
+
+/// End of the auxiliary vector
+const AT_NULL: u64 = 0;
+
+/// Why a program could not be loaded.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// The image is not a valid executable
+    Elf(ElfError),
+    /// A segment or the stack could not be mapped
+    Map(MapError),
+    /// The segment at this address lies in the kernel's part of the address
+    /// space. Programs linked at the usual `0x400000` end up there; link
+    /// them at [`USER_BASE`] or above instead.
+    LinkAddress(u64),
+    /// `argv` and `envp` do not fit on the stack
+    ArgumentsTooLarge,
+}
+
+impl From<ElfError> for LoadError {
+    fn from(error: ElfError) -> LoadError {
+        LoadError::Elf(error)
+    }
+}
+
+impl From<MapError> for LoadError {
+    fn from(error: MapError) -> LoadError {
+        LoadError::Map(error)
+    }
+}
+
+/// A loaded program: its address space and register state.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+#[derive(Debug)]
+pub struct Program {
+    address_space: AddressSpace,
+    context: UserContext,
+}
+
+impl Program {
+    /// Runs the program until it returns to the kernel, see
+    /// [`userspace::run`](super::run). Call again to resume it.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn run(&mut self) -> UserExit {
+        unsafe {
+            let previous = self.address_space.activate();
+            let exit = super::run(&mut self.context);
+            AddressSpace::switch_back(previous);
+            exit
+        }
+    }
+
+    /// The program's saved registers.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn context(&mut self) -> &mut UserContext {
+        &mut self.context
+    }
+
+    /// The program's address space.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn address_space(&mut self) -> &mut AddressSpace {
+        &mut self.address_space
+    }
+}
+
+/// Loads `image` into a fresh address space with a stack holding `argv` and
+/// `envp` in the System V layout, ready to [`run`](Program::run).
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Program, LoadError> {
+    let elf = Elf::parse(image)?;
+    let mut address_space = AddressSpace::new()?;
+    let no_execute = Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE);
+
+    for segment in elf.segments().filter(|segment| segment.is_load()) {
+        let mut flags = PageTableFlags::empty();
+        if segment.is_writable() {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !segment.is_executable() && no_execute {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        let start = VirtAddr::new(segment.vaddr);
+        address_space
+            .map_user(start, segment.memory_size, flags)
+            .map_err(|error| match error {
+                MapError::KernelRange => LoadError::LinkAddress(segment.vaddr),
+                error => LoadError::Map(error),
+            })?;
+        address_space.write(start, elf.segment_data(&segment))?;
+    }
+
+    let mut stack_flags = PageTableFlags::WRITABLE;
+    if no_execute {
+        stack_flags |= PageTableFlags::NO_EXECUTE;
+    }
+    let stack_bottom = VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE);
+    address_space.map_user(stack_bottom, USER_STACK_SIZE, stack_flags)?;
+    let (stack_pointer, argv_pointer) = build_stack(&address_space, argv, envp)?;
+
+    let mut context = UserContext::new(elf.entry(), stack_pointer);
+    context.rdi = argv.len() as u64;
+    context.rsi = argv_pointer;
+    Ok(Program {
+        address_space,
+        context,
+    })
+}
+
+/// Lays out the initial stack: the strings at the top, then (growing down)
+/// the auxiliary vector, `envp`, `argv` and `argc`, with `argc` 16-byte
+/// aligned at the returned stack pointer. Also returns the address of `argv`.
+fn build_stack(
+    address_space: &AddressSpace,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<(u64, u64), LoadError> {
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    let mut top = USER_STACK_TOP;
+    let mut push_string = |string: &str| -> Result<u64, LoadError> {
+        let size = string.len() as u64 + 1;
+        if top - stack_bottom < size {
+            return Err(LoadError::ArgumentsTooLarge);
+        }
+        top -= size;
+        address_space.write(VirtAddr::new(top), string.as_bytes())?;
+        address_space.write(VirtAddr::new(top + size - 1), &[0])?;
+        Ok(top)
+    };
+    let argv_strings = argv.iter().map(|arg| push_string(arg)).collect::<Result<Vec<_>, _>>()?;
+    let envp_strings = envp.iter().map(|env| push_string(env)).collect::<Result<Vec<_>, _>>()?;
+
+    let mut words = Vec::with_capacity(argv.len() + envp.len() + 5);
+    words.push(argv.len() as u64);
+    words.extend_from_slice(&argv_strings);
+    words.push(0);
+    words.extend_from_slice(&envp_strings);
+    words.push(0);
+    words.extend_from_slice(&[AT_NULL, 0]);
+
+    let size = (words.len() * mem::size_of::<u64>()) as u64;
+    if top - stack_bottom < size + 16 {
+        return Err(LoadError::ArgumentsTooLarge);
+    }
+    let stack_pointer = (top - size) & !0xF;
+    let bytes = unsafe { slice::from_raw_parts(words.as_ptr() as *const u8, size as usize) };
+    address_space.write(VirtAddr::new(stack_pointer), bytes)?;
+    Ok((stack_pointer, stack_pointer + 8))
+}
+
+/// Borrows an executable image the bootloader left at `start` in physical
+/// memory, so it can be passed to [`load`].
+///
+/// # Safety
+///
+/// `len` bytes at `start` must be reserved for the image and stay untouched
+/// while it is borrowed.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub unsafe fn image_from_physical(start: PhysAddr, len: usize) -> &'static [u8] {
+    slice::from_raw_parts(crate::memory::phys_to_virt(start).as_ptr(), len)
+}
+
+#[test_case]
+fn test_load_and_run_program() {
+    use super::elf::{self, PF_R, PF_X};
+    // mov edi, 7; mov eax, SYS_EXIT; syscall
+    const CODE: [u8; 12] = [0xBF, 7, 0, 0, 0, 0xB8, 1, 0, 0, 0, 0x0F, 0x05];
+
+    let image = elf::test_image(USER_BASE, USER_BASE, PF_R | PF_X, &CODE);
+    let mut program = load(&image, &["test"], &[]).unwrap();
+    assert_eq!(program.context().rip, USER_BASE);
+    assert_eq!(program.run(), UserExit::Exit { code: 7 });
+
+    let low = elf::test_image(0x40_0000, 0x40_0000, PF_R | PF_X, &CODE);
+    assert_eq!(load(&low, &[], &[]).err(), Some(LoadError::LinkAddress(0x40_0000)));
+}
@@ -0,0 +1,325 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Running code in ring 3.
+//!
+//! [`run`] enters user mode with `iretq` and returns once the program is
+//! interrupted back into the kernel: a fault, or a handler deciding to take
+//! the CPU back. The kernel stack is left exactly as it was, so `run` behaves
+//! like an ordinary function call.
+
+use crate::gdt;
+use crate::interrupts::trap::TrapFrame;
+use std3::__reexports::x86_64;
+use std3::arch::global_asm;
+use std3::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use std3::{ptr, sync::Mutex, time::Duration};
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub mod address_space;
+#[unstable(feature = "rinuxcore_elf", issue = "none")]
+pub mod elf;
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub mod handle;
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub mod loader;
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub mod process;
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub mod scheduler;
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub mod syscall;
+
+/// Flags user code may set: CF, PF, AF, ZF, SF, TF, DF and OF.
+const USER_RFLAGS: u64 = 0xDD5;
+/// Interrupts are always enabled in user mode, and bit 1 is reserved as 1.
+const FORCED_RFLAGS: u64 = 0x202;
+
+/// Register state of a user program.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UserContext {
+    /// `rax`
+    pub rax: u64,
+    /// `rbx`
+    pub rbx: u64,
+    /// `rcx`
+    pub rcx: u64,
+    /// `rdx`
+    pub rdx: u64,
+    /// `rsi`
+    pub rsi: u64,
+    /// `rdi`
+    pub rdi: u64,
+    /// `rbp`
+    pub rbp: u64,
+    /// `r8`
+    pub r8: u64,
+    /// `r9`
+    pub r9: u64,
+    /// `r10`
+    pub r10: u64,
+    /// `r11`
+    pub r11: u64,
+    /// `r12`
+    pub r12: u64,
+    /// `r13`
+    pub r13: u64,
+    /// `r14`
+    pub r14: u64,
+    /// `r15`
+    pub r15: u64,
+    /// `rip`
+    pub rip: u64,
+    /// `rsp`
+    pub rsp: u64,
+    /// `rflags`
+    pub rflags: u64,
+}
+
+impl UserContext {
+    /// A context that starts at `entry` with stack pointer `stack_top`.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn new(entry: u64, stack_top: u64) -> UserContext {
+        UserContext {
+            rip: entry,
+            rsp: stack_top,
+            rflags: FORCED_RFLAGS,
+            ..UserContext::default()
+        }
+    }
+}
+
+/// Why [`run`] returned.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserExit {
+    /// A page fault at `address`
+    PageFault {
+        /// Faulting address (CR2)
+        address: u64,
+        /// Page fault error code
+        error_code: u64,
+    },
+    /// Any other CPU exception
+    Exception {
+        /// Exception vector
+        vector: u8,
+        /// Error code, for the exceptions that push one
+        error_code: Option<u64>,
+    },
+    /// A hardware interrupt handler took the CPU back
+    Interrupt {
+        /// Interrupt vector
+        vector: u8,
+    },
+    /// The program called the `exit` system call
+    Exit {
+        /// Exit status
+        code: i64,
+    },
+    /// The program called the `yield` system call
+    Yield,
+    /// The program called the `sleep` system call
+    Sleep {
+        /// How long it asked to sleep
+        duration: Duration,
+    },
+}
+
+crate::per_cpu! {
+    static KERNEL_RSP: AtomicU64 = AtomicU64::new(0);
+    static CURRENT: AtomicPtr<UserContext> = AtomicPtr::new(ptr::null_mut());
+    static EXIT: Mutex<Option<UserExit>> = Mutex::new(None);
+}
+
+extern "C" {
+    fn rinux_enter_user(context: *const UserContext, kernel_rsp: *mut u64, cs: u64, ss: u64);
+    fn rinux_exit_user(kernel_rsp: u64) -> !;
+    static rinux_enter_user_iretq: u8;
+}
+
+// `rinux_enter_user` saves the callee-saved registers and the stack pointer,
+// then `iretq`s into the context. `rinux_exit_user` switches back to that
+// stack and returns from `rinux_enter_user` on its behalf.
+global_asm!(
+    ".global rinux_enter_user",
+    "rinux_enter_user:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rsi], rsp",
+    "push rcx",
+    "push qword ptr [rdi + 128]",
+    "push qword ptr [rdi + 136]",
+    "push rdx",
+    "push qword ptr [rdi + 120]",
+    "mov rax, [rdi]",
+    "mov rbx, [rdi + 8]",
+    "mov rcx, [rdi + 16]",
+    "mov rdx, [rdi + 24]",
+    "mov rsi, [rdi + 32]",
+    "mov rbp, [rdi + 48]",
+    "mov r8, [rdi + 56]",
+    "mov r9, [rdi + 64]",
+    "mov r10, [rdi + 72]",
+    "mov r11, [rdi + 80]",
+    "mov r12, [rdi + 88]",
+    "mov r13, [rdi + 96]",
+    "mov r14, [rdi + 104]",
+    "mov r15, [rdi + 112]",
+    "mov rdi, [rdi + 40]",
+    "swapgs",
+    ".global rinux_enter_user_iretq",
+    "rinux_enter_user_iretq:",
+    "iretq",
+    "",
+    ".global rinux_exit_user",
+    "rinux_exit_user:",
+    "mov rsp, rdi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+);
+
+/// Runs `context` in ring 3 until it is interrupted back into the kernel,
+/// then returns why. `context` is updated with the program's state at that
+/// point, so calling `run` again resumes it.
+///
+/// # Safety
+///
+/// The code and stack `context` points to must be mapped user accessible in
+/// the active page table, and must not alias kernel memory.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub unsafe fn run(context: &mut UserContext) -> UserExit {
+    let selectors = gdt::selectors();
+    let were_enabled = interrupts::are_enabled();
+    interrupts::disable();
+
+    context.rflags = (context.rflags & USER_RFLAGS) | FORCED_RFLAGS;
+    CURRENT.get().store(context, Ordering::SeqCst);
+    rinux_enter_user(
+        context,
+        KERNEL_RSP.get().as_ptr(),
+        u64::from(selectors.user_code_selector.0),
+        u64::from(selectors.user_data_selector.0),
+    );
+    CURRENT.get().store(ptr::null_mut(), Ordering::SeqCst);
+
+    let exit = EXIT.get().lock().take().expect("left user mode without an exit reason");
+    if were_enabled {
+        interrupts::enable();
+    }
+    exit
+}
+
+/// Returns `true` if `stack_frame` interrupted ring 3 code.
+pub(crate) fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+/// Returns `true` if `stack_frame` is a fault of the `iretq` into ring 3,
+/// which arrives in ring 0 after `GS_BASE` was already swapped to the user's.
+pub(crate) fn entering_user(stack_frame: &InterruptStackFrame) -> bool {
+    let iretq = ptr::addr_of!(rinux_enter_user_iretq) as u64;
+    stack_frame.instruction_pointer.as_u64() == iretq
+}
+
+/// Returns `true` if `frame` interrupted ring 3 code.
+pub(crate) fn trap_from_user(frame: &TrapFrame) -> bool {
+    frame.cs & 3 == 3
+}
+
+/// Ends the current [`run`] with `exit`, saving every register from `frame`
+/// so the program can be resumed exactly where it was interrupted.
+///
+/// # Safety
+///
+/// Same as [`return_to_kernel`], for handlers entered through `trap_entry!`.
+pub(crate) unsafe fn return_from_trap(exit: UserExit, frame: &TrapFrame) -> ! {
+    update_current(|context| {
+        *context = UserContext {
+            rax: frame.rax,
+            rbx: frame.rbx,
+            rcx: frame.rcx,
+            rdx: frame.rdx,
+            rsi: frame.rsi,
+            rdi: frame.rdi,
+            rbp: frame.rbp,
+            r8: frame.r8,
+            r9: frame.r9,
+            r10: frame.r10,
+            r11: frame.r11,
+            r12: frame.r12,
+            r13: frame.r13,
+            r14: frame.r14,
+            r15: frame.r15,
+            rip: frame.rip,
+            rsp: frame.rsp,
+            rflags: frame.rflags,
+        };
+    });
+    leave(exit)
+}
+
+/// Ends the current [`run`] with `exit`. Only the instruction pointer, stack
+/// pointer and flags are saved from `stack_frame`; the general purpose
+/// registers keep the values they had when the program was entered.
+///
+/// # Safety
+///
+/// Must be called from an interrupt handler whose `stack_frame` came from
+/// user mode, with any end-of-interrupt already sent. The handler's stack
+/// frame is abandoned.
+pub(crate) unsafe fn return_to_kernel(exit: UserExit, stack_frame: &InterruptStackFrame) -> ! {
+    update_current(|context| {
+        context.rip = stack_frame.instruction_pointer.as_u64();
+        context.rsp = stack_frame.stack_pointer.as_u64();
+        context.rflags = stack_frame.cpu_flags;
+    });
+    leave(exit)
+}
+
+fn update_current(f: impl FnOnce(&mut UserContext)) {
+    let context = CURRENT.get().load(Ordering::SeqCst);
+    if let Some(context) = unsafe { context.as_mut() } {
+        f(context);
+    }
+}
+
+unsafe fn leave(exit: UserExit) -> ! {
+    *EXIT.get().lock() = Some(exit);
+    rinux_exit_user(KERNEL_RSP.get().load(Ordering::SeqCst))
+}
@@ -0,0 +1,99 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! User processes.
+
+use super::handle::HandleTable;
+use super::loader::{self, LoadError, Program};
+use std3::fmt;
+use std3::sync::atomic::{AtomicU64, Ordering};
+
+/// Process identifier. Never reused.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Pid {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The raw number.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A user program with its own address space, user stack and handle table.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+#[derive(Debug)]
+pub struct Process {
+    pid: Pid,
+    program: Program,
+    handles: HandleTable,
+}
+
+impl Process {
+    /// Wraps a loaded program, giving it the console as handles 1 and 2.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn new(program: Program) -> Process {
+        Process {
+            pid: Pid::new(),
+            program,
+            handles: HandleTable::with_console(),
+        }
+    }
+
+    /// Loads `image` as a new process, see [`loader::load`].
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn from_image(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Process, LoadError> {
+        loader::load(image, argv, envp).map(Process::new)
+    }
+
+    /// The process identifier.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// The program: address space, user stack and registers.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn program(&mut self) -> &mut Program {
+        &mut self.program
+    }
+
+    /// The process's open handles.
+    #[unstable(feature = "rinuxcore_userspace", issue = "none")]
+    pub fn handles(&mut self) -> &mut HandleTable {
+        &mut self.handles
+    }
+}
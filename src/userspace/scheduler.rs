@@ -0,0 +1,272 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Round-robin scheduling of user processes.
+//!
+//! The scheduler is an ordinary executor task, so processes share the CPU
+//! with the kernel's own async work. Each turn it runs one process until the
+//! next timer tick or system call that gives up the CPU, then lets the
+//! executor poll its other tasks.
+//!
+//! ```rust
+//! use rinuxcore::userspace::scheduler;
+//!
+//! let mut executor = Executor::new();
+//! executor.spawn(Task::new(scheduler::run()));
+//! let pid = scheduler::spawn_image(INIT, &["init"], &[]).unwrap();
+//! executor.spawn(Task::new(async move {
+//!     let status = scheduler::wait(pid).await;
+//!     println!("init exited with {:?}", status);
+//! }));
+//! executor.run()
+//! ```
+
+use super::loader::LoadError;
+use super::process::{Pid, Process};
+use super::UserExit;
+use crate::print_err;
+use crate::task;
+use crate::time::Instant;
+use futures_util::task::AtomicWaker;
+use std3::collections::{BTreeMap, VecDeque};
+use std3::future::Future;
+use std3::pin::Pin;
+use std3::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use std3::task::{Context, Poll, Waker};
+use std3::{lazy_static, mem, ptr, sync::Mutex, vec::Vec};
+
+/// Exit status of a process killed by a fault.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub const KILLED: i64 = -1;
+
+/// Exit statuses kept for processes nobody has waited for yet; beyond this
+/// the oldest ones without a waiter are forgotten.
+const MAX_EXITED: usize = 64;
+
+struct Scheduler {
+    processes: BTreeMap<Pid, Process>,
+    ready: VecDeque<Pid>,
+    /// `(deadline in nanoseconds since boot, pid)`
+    sleeping: Vec<(u64, Pid)>,
+    exited: BTreeMap<Pid, i64>,
+    /// Kept after the process exits until one of them collects the status
+    waiters: BTreeMap<Pid, Vec<Waker>>,
+}
+
+lazy_static! {
+    static ref SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+        processes: BTreeMap::new(),
+        ready: VecDeque::new(),
+        sleeping: Vec::new(),
+        exited: BTreeMap::new(),
+        waiters: BTreeMap::new(),
+    });
+}
+
+/// The process `run` is executing, for system calls. Only set while the
+/// process is outside of `SCHEDULER`.
+static CURRENT: AtomicPtr<Process> = AtomicPtr::new(ptr::null_mut());
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+impl Scheduler {
+    fn wake_sleepers(&mut self, now: u64) {
+        let ready = &mut self.ready;
+        self.sleeping.retain(|&(deadline, pid)| {
+            if deadline <= now {
+                ready.push_back(pid);
+            }
+            deadline > now
+        });
+    }
+
+    /// Records `code` and drops `process`, freeing its address space.
+    fn finish(&mut self, process: Process, code: i64) {
+        let pid = process.pid();
+        drop(process);
+        self.exited.insert(pid, code);
+        if self.exited.len() > MAX_EXITED {
+            let waiters = &self.waiters;
+            let unwaited = self.exited.keys().copied().find(|pid| !waiters.contains_key(pid));
+            if let Some(oldest) = unwaited {
+                self.exited.remove(&oldest);
+            }
+        }
+        for waker in self.waiters.get_mut(&pid).map(mem::take).unwrap_or_default() {
+            waker.wake();
+        }
+    }
+}
+
+/// Adds `process` to the end of the run queue.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub fn spawn(process: Process) -> Pid {
+    let pid = process.pid();
+    let mut scheduler = SCHEDULER.lock();
+    scheduler.processes.insert(pid, process);
+    scheduler.ready.push_back(pid);
+    WAKER.wake();
+    pid
+}
+
+/// Loads `image` and [`spawn`]s it.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub fn spawn_image(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, LoadError> {
+    Process::from_image(image, argv, envp).map(spawn)
+}
+
+/// Ends `pid` with exit status `code`. Returns `false` if it is not waiting
+/// to run; a process cannot kill itself this way, it should call `exit`.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub fn kill(pid: Pid, code: i64) -> bool {
+    let mut scheduler = SCHEDULER.lock();
+    match scheduler.processes.remove(&pid) {
+        Some(process) => {
+            scheduler.ready.retain(|&ready| ready != pid);
+            scheduler.sleeping.retain(|&(_, sleeping)| sleeping != pid);
+            scheduler.finish(process, code);
+            true
+        }
+        None => false,
+    }
+}
+
+/// The process currently running, when called from a system call.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub fn current_pid() -> Option<Pid> {
+    with_current(|process| process.pid())
+}
+
+/// Runs `f` on the process currently running, if any.
+pub(crate) fn with_current<R>(f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    let process = CURRENT.load(Ordering::SeqCst);
+    unsafe { process.as_mut() }.map(f)
+}
+
+/// Called on every timer interrupt.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    WAKER.wake();
+}
+
+/// Waits for `pid` to exit and returns its status, or `None` if there is no
+/// such process. The status is handed out once: later waits for the same
+/// process, and waits for a process that exited long ago, return `None`.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub fn wait(pid: Pid) -> Wait {
+    Wait { pid }
+}
+
+/// Future returned by [`wait`].
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+#[derive(Debug)]
+pub struct Wait {
+    pid: Pid,
+}
+
+impl Future for Wait {
+    type Output = Option<i64>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<i64>> {
+        let mut scheduler = SCHEDULER.lock();
+        if let Some(code) = scheduler.exited.remove(&self.pid) {
+            scheduler.waiters.remove(&self.pid);
+            return Poll::Ready(Some(code));
+        }
+        if !scheduler.processes.contains_key(&self.pid) {
+            return Poll::Ready(None);
+        }
+        scheduler
+            .waiters
+            .entry(self.pid)
+            .or_default()
+            .push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// The scheduler task. Spawn it once on the executor; it never finishes.
+#[unstable(feature = "rinuxcore_userspace", issue = "none")]
+pub async fn run() {
+    loop {
+        let next = {
+            let mut scheduler = SCHEDULER.lock();
+            scheduler.wake_sleepers(Instant::now().as_nanos());
+            let pid = scheduler.ready.pop_front();
+            pid.and_then(|pid| scheduler.processes.remove(&pid))
+        };
+        match next {
+            Some(process) => {
+                run_slice(process);
+                task::yield_now().await;
+            }
+            None => NextTick(TICKS.load(Ordering::Relaxed)).await,
+        }
+    }
+}
+
+/// Runs `process` until it gives up the CPU and files it accordingly.
+fn run_slice(mut process: Process) {
+    // System calls reach the process through `CURRENT`, so it is only
+    // touched through this one pointer until the slice is over.
+    let current = ptr::addr_of_mut!(process);
+    CURRENT.store(current, Ordering::SeqCst);
+    let exit = unsafe { (*current).program().run() };
+    CURRENT.store(ptr::null_mut(), Ordering::SeqCst);
+
+    let pid = process.pid();
+    let mut scheduler = SCHEDULER.lock();
+    match exit {
+        UserExit::Interrupt { .. } | UserExit::Yield => {
+            scheduler.processes.insert(pid, process);
+            scheduler.ready.push_back(pid);
+        }
+        UserExit::Sleep { duration } => {
+            let deadline = Instant::now().as_nanos().saturating_add(duration.as_nanos() as u64);
+            scheduler.processes.insert(pid, process);
+            scheduler.sleeping.push((deadline, pid));
+        }
+        UserExit::Exit { code } => scheduler.finish(process, code),
+        UserExit::PageFault { .. } | UserExit::Exception { .. } => {
+            print_err!("[ERR] process {} killed: {:?}\n", pid, exit);
+            scheduler.finish(process, KILLED);
+        }
+    }
+}
+
+/// Completes after the next timer tick or [`spawn`].
+struct NextTick(u64);
+
+impl Future for NextTick {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        WAKER.register(cx.waker());
+        if TICKS.load(Ordering::Relaxed) != self.0 || !SCHEDULER.lock().ready.is_empty() {
+            WAKER.take();
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
@@ -0,0 +1,380 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! System calls through `SYSCALL`/`SYSRET`.
+//!
+//! The number goes in `rax` and up to six arguments in `rdi`, `rsi`, `rdx`,
+//! `r10`, `r8` and `r9`, as on Linux. The result comes back in `rax`;
+//! negative values are errors. Numbers below [`FIRST_USER_SYSCALL`] are the
+//! built-in calls, the rest can be claimed with [`register`].
+
+use super::{leave, update_current, UserExit, USER_RFLAGS, FORCED_RFLAGS};
+use crate::time::{Instant, SystemTime};
+use super::handle::{Console, Handle};
+use super::scheduler;
+use crate::{gdt, memory, per_cpu, vga_buffer::print_ok};
+use std3::__reexports::x86_64;
+use std3::arch::global_asm;
+use std3::{ptr, slice, sync::Mutex, time::Duration};
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
+use x86_64::VirtAddr;
+
+/// `write(handle, buffer, len)`: writes to one of the process's handles; see
+/// [`handle`](super::handle). Outside the scheduler, handle 1 is the screen
+/// and 2 the screen in red. Returns the number of bytes written.
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub const SYS_WRITE: u64 = 0;
+/// `exit(code)`: ends the program.
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub const SYS_EXIT: u64 = 1;
+/// `yield()`: gives up the CPU.
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub const SYS_YIELD: u64 = 2;
+/// `sleep(nanoseconds)`: gives up the CPU for at least that long.
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub const SYS_SLEEP: u64 = 3;
+/// `get_time(clock)`: nanoseconds since boot for [`CLOCK_MONOTONIC`], since
+/// the Unix epoch for [`CLOCK_REALTIME`].
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub const SYS_GET_TIME: u64 = 4;
+
+/// Clock argument of [`SYS_GET_TIME`]
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub const CLOCK_MONOTONIC: u64 = 0;
+/// Clock argument of [`SYS_GET_TIME`]
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub const CLOCK_REALTIME: u64 = 1;
+
+/// Bad handle number
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub const EBADF: i64 = -9;
+/// Bad address
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub const EFAULT: i64 = -14;
+/// Invalid argument
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub const EINVAL: i64 = -22;
+/// No such system call
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub const ENOSYS: i64 = -38;
+
+/// First number available to [`register`].
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub const FIRST_USER_SYSCALL: u64 = 32;
+/// Size of the dispatch table.
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub const MAX_SYSCALLS: u64 = 128;
+
+/// A registered system call. Gets the six argument registers and returns the
+/// value for `rax`. Runs with interrupts disabled.
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub type SyscallHandler = fn(args: [u64; 6]) -> i64;
+
+/// Why [`register`] refused a handler.
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// The number is a built-in or beyond [`MAX_SYSCALLS`]
+    OutOfRange,
+    /// Another handler already has this number
+    Taken,
+}
+
+static HANDLERS: Mutex<[Option<SyscallHandler>; MAX_SYSCALLS as usize]> =
+    Mutex::new([None; MAX_SYSCALLS as usize]);
+
+/// Makes `handler` answer system call `number`.
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub fn register(number: u64, handler: SyscallHandler) -> Result<(), RegisterError> {
+    if !(FIRST_USER_SYSCALL..MAX_SYSCALLS).contains(&number) {
+        return Err(RegisterError::OutOfRange);
+    }
+    let mut handlers = HANDLERS.lock();
+    let slot = &mut handlers[number as usize];
+    if slot.is_some() {
+        return Err(RegisterError::Taken);
+    }
+    *slot = Some(handler);
+    Ok(())
+}
+
+/// Removes the handler for `number`, returning it.
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub fn unregister(number: u64) -> Option<SyscallHandler> {
+    if !(FIRST_USER_SYSCALL..MAX_SYSCALLS).contains(&number) {
+        return None;
+    }
+    HANDLERS.lock()[number as usize].take()
+}
+
+/// Registers saved by the entry stub, lowest address first.
+#[repr(C)]
+#[derive(Debug)]
+struct SyscallFrame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    /// User RFLAGS, saved there by `SYSCALL`
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    /// User RIP, saved there by `SYSCALL`
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+    rsp: u64,
+}
+
+const STACK_SIZE: usize = 4096 * 4;
+static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+extern "C" {
+    fn rinux_syscall_entry();
+}
+
+// The kernel stack and the scratch slot for the user stack pointer live in
+// the CPU's per-CPU block (see `per_cpu::CpuBlock`). Interrupts are masked on
+// entry (see `init`), so neither can be reused under us.
+global_asm!(
+    ".global rinux_syscall_entry",
+    "rinux_syscall_entry:",
+    "swapgs",
+    "mov gs:[16], rsp",
+    "mov rsp, gs:[8]",
+    "push qword ptr gs:[16]",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "call rinux_syscall_dispatch",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "pop rsp",
+    "swapgs",
+    "sysretq",
+);
+
+/// Points `LSTAR` at the entry stub and enables `SYSCALL`.
+pub(crate) fn init() {
+    // Aligned so the stub's pushes leave `rsp` 16-byte aligned at the call
+    // into Rust, as the System V ABI requires.
+    let top = VirtAddr::from_ptr(ptr::addr_of!(STACK)) + STACK_SIZE;
+    enable(top.align_down(16u64));
+    unsafe {
+        if !crate::CONFIG.quiet_boot {
+            print_ok!("[OK] System calls initialized\n");
+        }
+    }
+}
+
+/// Enables `SYSCALL` on an application processor, whose MSRs start out
+/// cleared, with a system call stack of its own.
+pub(crate) fn init_ap() -> Result<(), MapToError<Size4KiB>> {
+    enable(memory::alloc_stack(STACK_SIZE as u64)?);
+    Ok(())
+}
+
+/// Programs the running CPU's `SYSCALL` MSRs, with `stack_top` for its
+/// system calls to run on.
+fn enable(stack_top: VirtAddr) {
+    let selectors = gdt::selectors();
+    per_cpu::set_syscall_stack(stack_top);
+    unsafe {
+        Star::write(
+            selectors.user_code_selector,
+            selectors.user_data_selector,
+            selectors.code_selector,
+            selectors.data_selector,
+        )
+        .expect("GDT layout is not SYSRET compatible");
+        LStar::write(VirtAddr::from_ptr(rinux_syscall_entry as *const ()));
+        SFMask::write(
+            RFlags::INTERRUPT_FLAG
+                | RFlags::DIRECTION_FLAG
+                | RFlags::TRAP_FLAG
+                | RFlags::ALIGNMENT_CHECK,
+        );
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+#[no_mangle]
+extern "C" fn rinux_syscall_dispatch(frame: &mut SyscallFrame) {
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    let result = match frame.rax {
+        SYS_WRITE => sys_write(args[0], args[1], args[2]),
+        SYS_EXIT => leave_with(frame, UserExit::Exit { code: args[0] as i64 }),
+        SYS_YIELD => leave_with(frame, UserExit::Yield),
+        SYS_SLEEP => {
+            let duration = Duration::from_nanos(args[0]);
+            leave_with(frame, UserExit::Sleep { duration })
+        }
+        SYS_GET_TIME => sys_get_time(args[0]),
+        number if number < MAX_SYSCALLS => {
+            let handler = HANDLERS.lock()[number as usize];
+            handler.map_or(ENOSYS, |handler| handler(args))
+        }
+        _ => ENOSYS,
+    };
+    frame.rax = result as u64;
+    // SYSRET loads RIP and RFLAGS from these; keep them sane.
+    frame.r11 = (frame.r11 & USER_RFLAGS) | FORCED_RFLAGS;
+    frame.rcx = VirtAddr::new_truncate(frame.rcx).as_u64();
+}
+
+/// Ends the current `userspace::run` with `exit`. The program resumes after
+/// the `syscall` instruction with `rax` = 0 if it is run again.
+fn leave_with(frame: &SyscallFrame, exit: UserExit) -> ! {
+    update_current(|context| {
+        *context = super::UserContext {
+            rax: 0,
+            rbx: frame.rbx,
+            rcx: frame.rcx,
+            rdx: frame.rdx,
+            rsi: frame.rsi,
+            rdi: frame.rdi,
+            rbp: frame.rbp,
+            r8: frame.r8,
+            r9: frame.r9,
+            r10: frame.r10,
+            r11: frame.r11,
+            r12: frame.r12,
+            r13: frame.r13,
+            r14: frame.r14,
+            r15: frame.r15,
+            rip: frame.rcx,
+            rsp: frame.rsp,
+            rflags: frame.r11,
+        };
+    });
+    unsafe { leave(exit) }
+}
+
+fn sys_write(fd: u64, buffer: u64, len: u64) -> i64 {
+    let bytes = match user_slice(buffer, len, false) {
+        Some(bytes) => bytes,
+        None => return EFAULT,
+    };
+    let written = scheduler::with_current(|process| {
+        process.handles().get(fd).map_or(EBADF, |handle| handle.write(bytes))
+    });
+    match (written, fd) {
+        (Some(written), _) => written,
+        (None, 1) => Console::Output.write(bytes),
+        (None, 2) => Console::Error.write(bytes),
+        (None, _) => EBADF,
+    }
+}
+
+fn sys_get_time(clock: u64) -> i64 {
+    match clock {
+        CLOCK_MONOTONIC => Instant::now().as_nanos() as i64,
+        CLOCK_REALTIME => SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as i64),
+        _ => EINVAL,
+    }
+}
+
+/// Checks that `len` bytes at `address` are user memory, mapped and, if
+/// `writable`, writable, in the active address space.
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub fn validate_user_range(address: u64, len: u64, writable: bool) -> bool {
+    use x86_64::structures::paging::PageTableFlags;
+
+    const USER_END: u64 = 0x0000_8000_0000_0000;
+    let end = match address.checked_add(len) {
+        Some(end) if end <= USER_END => end,
+        _ => return false,
+    };
+    if len == 0 {
+        return true;
+    }
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if writable {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let mut page = address & !0xFFF;
+    while page < end {
+        match crate::memory::page_flags(VirtAddr::new(page)) {
+            Some(flags) if flags.contains(required) => {}
+            _ => return false,
+        }
+        page += 4096;
+    }
+    true
+}
+
+/// Borrows `len` bytes of user memory at `address` after validating them.
+#[unstable(feature = "rinuxcore_syscall", issue = "none")]
+pub fn user_slice(address: u64, len: u64, writable: bool) -> Option<&'static [u8]> {
+    if !validate_user_range(address, len, writable) {
+        return None;
+    }
+    if len == 0 {
+        return Some(&[]);
+    }
+    Some(unsafe { slice::from_raw_parts(address as *const u8, len as usize) })
+}
+
+#[test_case]
+fn test_kernel_addresses_are_rejected() {
+    assert!(!validate_user_range(0xFFFF_8000_0000_0000, 8, false));
+    assert!(!validate_user_range(u64::MAX - 4, 8, false));
+    assert!(!validate_user_range(0, 8, false));
+}
@@ -0,0 +1,311 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+//! Writes the symbol table rinuxcore's backtraces use into a linked kernel.
+//!
+//! Usage: `ksymgen <kernel-elf>`, or `ksymgen --run <command>... <kernel-elf>
+//! [args]...` as a cargo runner, which adds the table and then runs the
+//! command, usually `bootimage runner`, with the remaining arguments.
+//!
+//! Collects every sized function symbol from `.symtab`, demangles it and
+//! appends the table to the file as a read-only `PT_LOAD` segment of exactly
+//! its size, taking over the `PT_GNU_STACK` program header the linker emits.
+//! Its address and length go into the kernel's `.rinux_ksyms` section, so
+//! the file can be turned into a boot image as usual afterwards. See
+//! `rinuxcore::backtrace` for the layout.
+
+use std::{env, fs, process::{self, Command}};
+
+const SECTION_NAME: &str = ".rinux_ksyms";
+const MAGIC: &[u8; 8] = b"RKSYMS01";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+/// Longest name kept; longer ones are cut off.
+const MAX_NAME_LEN: usize = 160;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+const PT_NULL: u32 = 0;
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474_E551;
+const PF_R: u32 = 4;
+const PAGE_SIZE: u64 = 4096;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+struct Section {
+    name: u32,
+    kind: u32,
+    offset: usize,
+    size: usize,
+    link: u32,
+}
+
+struct Symbol {
+    address: u64,
+    size: u64,
+    name: String,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, String> {
+    bytes
+        .get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| format!("truncated ELF at {:#x}", offset))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, String> {
+    let mut value = [0; 4];
+    value.copy_from_slice(
+        bytes
+            .get(offset..offset + 4)
+            .ok_or_else(|| format!("truncated ELF at {:#x}", offset))?,
+    );
+    Ok(u32::from_le_bytes(value))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, String> {
+    let mut value = [0; 8];
+    value.copy_from_slice(
+        bytes
+            .get(offset..offset + 8)
+            .ok_or_else(|| format!("truncated ELF at {:#x}", offset))?,
+    );
+    Ok(u64::from_le_bytes(value))
+}
+
+fn c_str(bytes: &[u8], offset: usize) -> Result<&str, String> {
+    let tail = bytes
+        .get(offset..)
+        .ok_or_else(|| format!("string offset {:#x} out of range", offset))?;
+    let len = tail.iter().position(|b| *b == 0).unwrap_or(tail.len());
+    std::str::from_utf8(&tail[..len]).map_err(|e| e.to_string())
+}
+
+fn sections(elf: &[u8]) -> Result<Vec<Section>, String> {
+    if elf.get(..4) != Some(b"\x7fELF") || elf.get(4) != Some(&2) || elf.get(5) != Some(&1) {
+        return Err("not a little endian ELF64 file".into());
+    }
+    let table = read_u64(elf, 0x28)? as usize;
+    let entry_size = read_u16(elf, 0x3A)? as usize;
+    let count = read_u16(elf, 0x3C)? as usize;
+
+    (0..count)
+        .map(|i| {
+            let header = table + i * entry_size;
+            Ok(Section {
+                name: read_u32(elf, header)?,
+                kind: read_u32(elf, header + 4)?,
+                offset: read_u64(elf, header + 24)? as usize,
+                size: read_u64(elf, header + 32)? as usize,
+                link: read_u32(elf, header + 40)?,
+            })
+        })
+        .collect()
+}
+
+fn function_symbols(elf: &[u8], sections: &[Section]) -> Result<Vec<Symbol>, String> {
+    let symtab = sections
+        .iter()
+        .find(|s| s.kind == SHT_SYMTAB)
+        .ok_or("no .symtab; was the kernel stripped?")?;
+    let strtab = sections
+        .get(symtab.link as usize)
+        .ok_or("symbol string table missing")?;
+    let strings = elf
+        .get(strtab.offset..strtab.offset + strtab.size)
+        .ok_or("symbol string table out of range")?;
+
+    let mut symbols = Vec::new();
+    for entry in (symtab.offset..symtab.offset + symtab.size).step_by(24) {
+        let info = *elf.get(entry + 4).ok_or("truncated symbol table")?;
+        let address = read_u64(elf, entry + 8)?;
+        let size = read_u64(elf, entry + 16)?;
+        if info & 0xF != STT_FUNC || address == 0 || size == 0 {
+            continue;
+        }
+        let mangled = c_str(strings, read_u32(elf, entry)? as usize)?;
+        let mut name = format!("{:#}", rustc_demangle::demangle(mangled));
+        if name.len() > MAX_NAME_LEN {
+            let mut end = MAX_NAME_LEN;
+            while !name.is_char_boundary(end) {
+                end -= 1;
+            }
+            name.truncate(end);
+        }
+        symbols.push(Symbol {
+            address,
+            size,
+            name,
+        });
+    }
+
+    symbols.sort_by_key(|s| s.address);
+    symbols.dedup_by_key(|s| s.address);
+    Ok(symbols)
+}
+
+fn encode(symbols: &[Symbol]) -> Vec<u8> {
+    let strings_offset = HEADER_SIZE + symbols.len() * ENTRY_SIZE;
+    let mut blob = Vec::new();
+    blob.extend_from_slice(MAGIC);
+    blob.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    blob.extend_from_slice(&(strings_offset as u32).to_le_bytes());
+
+    let mut strings = Vec::new();
+    for symbol in symbols {
+        blob.extend_from_slice(&symbol.address.to_le_bytes());
+        blob.extend_from_slice(&(symbol.size.min(u32::MAX as u64) as u32).to_le_bytes());
+        blob.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        strings.extend_from_slice(symbol.name.as_bytes());
+        strings.push(0);
+    }
+    blob.extend_from_slice(&strings);
+    blob
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u64(bytes: &mut [u8], offset: usize, value: u64) {
+    bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn align_up(value: u64) -> u64 {
+    (value + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Adds the symbol table to the kernel at `path`. Returns the number of
+/// symbols, or `None` if an earlier run already added it.
+fn run(path: &str) -> Result<Option<usize>, String> {
+    let mut elf = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let sections = sections(&elf)?;
+
+    let shstrndx = read_u16(&elf, 0x3E)? as usize;
+    let names = sections.get(shstrndx).ok_or("section name table missing")?;
+    let target = sections
+        .iter()
+        .find(|s| c_str(&elf, names.offset + s.name as usize) == Ok(SECTION_NAME))
+        .ok_or_else(|| format!("no {} section; is this a rinuxcore kernel?", SECTION_NAME))?;
+    if target.size < 16 {
+        return Err(format!("{} is too small", SECTION_NAME));
+    }
+    if read_u64(&elf, target.offset + 8)? != 0 {
+        return Ok(None);
+    }
+
+    // Program headers: the new segment goes after every loaded one, in the
+    // slot of one the bootloader ignores.
+    let table = read_u64(&elf, 0x20)? as usize;
+    let count = read_u16(&elf, 0x38)? as usize;
+    let mut slot = None;
+    let mut end = 0;
+    for index in 0..count {
+        let header = table + index * PROGRAM_HEADER_SIZE;
+        match read_u32(&elf, header)? {
+            PT_LOAD => {
+                let segment_end = read_u64(&elf, header + 16)? + read_u64(&elf, header + 40)?;
+                end = end.max(segment_end);
+            }
+            PT_GNU_STACK | PT_NULL => slot = slot.or(Some(header)),
+            _ => {}
+        }
+    }
+    let slot = slot.ok_or("no PT_GNU_STACK program header to take over")?;
+
+    let symbols = function_symbols(&elf, &sections)?;
+    let blob = encode(&symbols);
+    let address = align_up(end);
+    let offset = align_up(elf.len() as u64);
+    let len = blob.len() as u64;
+    elf.resize(offset as usize, 0);
+    elf.extend_from_slice(&blob);
+
+    write_u32(&mut elf, slot, PT_LOAD);
+    write_u32(&mut elf, slot + 4, PF_R);
+    write_u64(&mut elf, slot + 8, offset);
+    write_u64(&mut elf, slot + 16, address);
+    write_u64(&mut elf, slot + 24, address);
+    write_u64(&mut elf, slot + 32, len);
+    write_u64(&mut elf, slot + 40, len);
+    write_u64(&mut elf, slot + 48, PAGE_SIZE);
+    write_u64(&mut elf, target.offset, address);
+    write_u64(&mut elf, target.offset + 8, len);
+
+    fs::write(path, &elf).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(Some(symbols.len()))
+}
+
+/// The kernel is the first argument after the program to run that is an ELF
+/// file; everything before it is the command.
+fn kernel_argument(command: &[String]) -> Option<usize> {
+    command
+        .iter()
+        .skip(1)
+        .position(|arg| fs::read(arg).is_ok_and(|bytes| bytes.starts_with(b"\x7fELF")))
+        .map(|index| index + 1)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (path, command) = match args.first().map(String::as_str) {
+        Some("--run") => {
+            let rest = &args[1..];
+            match kernel_argument(rest) {
+                Some(index) => (rest[index].clone(), Some(rest)),
+                _ => usage(),
+            }
+        }
+        Some(path) if args.len() == 1 => (path.to_string(), None),
+        _ => usage(),
+    };
+
+    match run(&path) {
+        Ok(Some(count)) => println!("ksymgen: wrote {} symbols into {}", count, path),
+        Ok(None) => {}
+        // Still boot without symbols when running; backtraces show addresses
+        Err(error) if command.is_some() => eprintln!("ksymgen: warning: {}", error),
+        Err(error) => {
+            eprintln!("ksymgen: {}", error);
+            process::exit(1);
+        }
+    }
+
+    if let Some(command) = command {
+        let status = Command::new(&command[0])
+            .args(&command[1..])
+            .status()
+            .unwrap_or_else(|error| {
+                eprintln!("ksymgen: {}: {}", command[0], error);
+                process::exit(1);
+            });
+        process::exit(status.code().unwrap_or(1));
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: ksymgen <kernel-elf>");
+    eprintln!("       ksymgen --run <command>... <kernel-elf> [args]...");
+    process::exit(2);
+}
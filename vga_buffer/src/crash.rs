@@ -0,0 +1,179 @@
+//
+// MIT License
+//
+// Copyright (c) 2022 AtomicGamer9523
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+use crate::writers::{Buffer, Color, ColorCode, ScreenChar, BUFFER_HEIGHT, BUFFER_WIDTH};
+use std3::fmt;
+use std3::sync::atomic::{AtomicBool, Ordering};
+
+static CRASHED: AtomicBool = AtomicBool::new(false);
+
+// CP437 double-line box drawing characters
+const TOP_LEFT: u8 = 0xC9;
+const TOP_RIGHT: u8 = 0xBB;
+const BOTTOM_LEFT: u8 = 0xC8;
+const BOTTOM_RIGHT: u8 = 0xBC;
+const HORIZONTAL: u8 = 0xCD;
+const VERTICAL: u8 = 0xBA;
+
+const FIRST_ROW: usize = 2;
+const LAST_ROW: usize = BUFFER_HEIGHT - 2;
+const FIRST_COLUMN: usize = 3;
+const LAST_COLUMN: usize = BUFFER_WIDTH - 3;
+
+/// Returns `true` once a [`CrashScreen`] has been drawn. From then on the
+/// print functions leave the screen alone.
+pub fn is_crashed() -> bool {
+    CRASHED.load(Ordering::Relaxed)
+}
+
+/// A full-screen, framed crash report drawn straight into the VGA buffer.
+///
+/// Text written through [`fmt::Write`] wraps inside the frame; anything past
+/// the last row is dropped.
+#[derive(Debug)]
+pub struct CrashScreen {
+    buffer: &'static mut Buffer,
+    row: usize,
+    column: usize,
+    color_code: ColorCode,
+}
+
+impl CrashScreen {
+    /// Clears the screen and draws an empty frame titled `kind`, with
+    /// `version` in the bottom border. Every later print is discarded.
+    ///
+    /// # Safety
+    ///
+    /// The screen writes the VGA buffer that the global writer also points
+    /// at. Nothing may be printing, on this CPU or another, while it is in
+    /// use, and only one may exist at a time: meant for crash paths that
+    /// have stopped everything else.
+    pub unsafe fn new(kind: &str, version: &str) -> CrashScreen {
+        CRASHED.store(true, Ordering::SeqCst);
+        let mut screen = CrashScreen {
+            buffer: &mut *(0xb8000 as *mut Buffer),
+            row: FIRST_ROW,
+            column: FIRST_COLUMN,
+            color_code: ColorCode::new(Color::White, Color::Blue),
+        };
+        screen.draw_frame();
+        screen.draw_label(0, kind.as_bytes(), ColorCode::new(Color::Yellow, Color::Blue));
+        screen.draw_label(BUFFER_HEIGHT - 1, version.as_bytes(), screen.color_code);
+        screen
+    }
+
+    /// Writes `label: value` on its own line, with the label highlighted.
+    pub fn field(&mut self, label: &str, value: fmt::Arguments) {
+        self.start_line();
+        let color_code = self.color_code;
+        self.color_code = ColorCode::new(Color::Yellow, Color::Blue);
+        self.write_text(label);
+        self.write_text(": ");
+        self.color_code = color_code;
+        let _ = fmt::Write::write_fmt(self, value);
+        self.start_line();
+    }
+
+    fn put(&mut self, row: usize, column: usize, byte: u8, color_code: ColorCode) {
+        self.buffer.chars[row][column].write(ScreenChar {
+            ascii_character: byte,
+            color_code,
+        });
+    }
+
+    fn draw_frame(&mut self) {
+        let color_code = self.color_code;
+        let bottom = BUFFER_HEIGHT - 1;
+        let right = BUFFER_WIDTH - 1;
+        for row in 0..BUFFER_HEIGHT {
+            for column in 0..BUFFER_WIDTH {
+                let byte = match (row, column) {
+                    (0, 0) => TOP_LEFT,
+                    (0, c) if c == right => TOP_RIGHT,
+                    (r, 0) if r == bottom => BOTTOM_LEFT,
+                    (r, c) if r == bottom && c == right => BOTTOM_RIGHT,
+                    (0, _) => HORIZONTAL,
+                    (r, _) if r == bottom => HORIZONTAL,
+                    (_, 0) => VERTICAL,
+                    (_, c) if c == right => VERTICAL,
+                    _ => b' ',
+                };
+                self.put(row, column, byte, color_code);
+            }
+        }
+    }
+
+    fn draw_label(&mut self, row: usize, text: &[u8], color_code: ColorCode) {
+        let text = &text[..text.len().min(BUFFER_WIDTH - 6)];
+        let start = (BUFFER_WIDTH - text.len() - 2) / 2;
+        self.put(row, start, b' ', color_code);
+        for (i, &byte) in text.iter().enumerate() {
+            self.put(row, start + 1 + i, printable(byte), color_code);
+        }
+        self.put(row, start + 1 + text.len(), b' ', color_code);
+    }
+
+    fn start_line(&mut self) {
+        if self.column != FIRST_COLUMN {
+            self.new_line();
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.row += 1;
+        self.column = FIRST_COLUMN;
+    }
+
+    fn write_text(&mut self, s: &str) {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.new_line();
+                continue;
+            }
+            if self.column > LAST_COLUMN {
+                self.new_line();
+            }
+            if self.row > LAST_ROW {
+                return;
+            }
+            let color_code = self.color_code;
+            self.put(self.row, self.column, printable(byte), color_code);
+            self.column += 1;
+        }
+    }
+}
+
+impl fmt::Write for CrashScreen {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_text(s);
+        Ok(())
+    }
+}
+
+fn printable(byte: u8) -> u8 {
+    match byte {
+        0x20..=0x7e => byte,
+        _ => 0xfe,
+    }
+}
@@ -36,6 +36,8 @@
 use std3::__reexports::x86_64::instructions::interrupts;
 mod writers;
 pub use writers::*;
+mod crash;
+pub use crash::{is_crashed, CrashScreen};
 
 
 /// Prints a string to the screen, appending a newline.
@@ -80,6 +82,9 @@ macro_rules! print_to_line {
 
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
+    if is_crashed() {
+        return;
+    }
     interrupts::without_interrupts(|| {
         WRITER.lock().write_fmt(args).unwrap();
     });
@@ -87,6 +92,9 @@ pub fn _print(args: fmt::Arguments) {
 
 #[doc(hidden)]
 pub fn _print_logo(args: fmt::Arguments) {
+    if is_crashed() {
+        return;
+    }
     interrupts::without_interrupts(|| {
         LOGOWRITER.lock().write_fmt(args).unwrap();
     });
@@ -94,6 +102,9 @@ pub fn _print_logo(args: fmt::Arguments) {
 
 #[doc(hidden)]
 pub fn _print_ok(args: fmt::Arguments) {
+    if is_crashed() {
+        return;
+    }
     interrupts::without_interrupts(|| {
         OKWRITER.lock().write_fmt(args).unwrap();
     });
@@ -101,6 +112,9 @@ pub fn _print_ok(args: fmt::Arguments) {
 
 #[doc(hidden)]
 pub fn _print_err(args: fmt::Arguments) {
+    if is_crashed() {
+        return;
+    }
     interrupts::without_interrupts(|| {
         ERRWRITER.lock().write_fmt(args).unwrap();
     });
@@ -108,6 +122,9 @@ pub fn _print_err(args: fmt::Arguments) {
 
 #[doc(hidden)]
 pub fn _print_warn(args: fmt::Arguments) {
+    if is_crashed() {
+        return;
+    }
     interrupts::without_interrupts(|| {
         WARNWRITER.lock().write_fmt(args).unwrap();
     });
@@ -115,10 +132,33 @@ pub fn _print_warn(args: fmt::Arguments) {
 
 #[doc(hidden)]
 pub fn _print_info(args: fmt::Arguments) {
+    if is_crashed() {
+        return;
+    }
     interrupts::without_interrupts(|| {
         INFOWRITER.lock().write_fmt(args).unwrap();
     });
 }
+/// Releases every writer lock, even if it is held.
+///
+/// # Safety
+///
+/// Only for crash paths that will never return to the lock holder, such as
+/// a panic handler that runs with interrupts disabled.
+pub unsafe fn force_unlock() {
+    for writer in [
+        &*WRITER,
+        &*INFOWRITER,
+        &*LOGOWRITER,
+        &*OKWRITER,
+        &*TRACEWRITER,
+        &*WARNWRITER,
+        &*ERRWRITER,
+    ] {
+        writer.force_unlock();
+    }
+}
+
 #[doc(hidden)]
 pub fn __set_init_rinux(f: fn()) {
     unsafe { RINUX_INIT_FN = Some(f) };
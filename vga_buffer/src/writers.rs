@@ -123,7 +123,7 @@ pub enum Color {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
-struct ColorCode(u8);
+pub(crate) struct ColorCode(u8);
 
 impl ColorCode {
     pub fn new(foreground: Color, background: Color) -> ColorCode {
@@ -137,9 +137,9 @@ impl ColorCode {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
-struct ScreenChar {
-    ascii_character: u8,
-    color_code: ColorCode,
+pub(crate) struct ScreenChar {
+    pub(crate) ascii_character: u8,
+    pub(crate) color_code: ColorCode,
 }
 impl Deref for ScreenChar {
     type Target = ScreenChar;
@@ -156,8 +156,8 @@ impl DerefMut for ScreenChar {
 }
 
 
-const BUFFER_WIDTH:  usize = 80;
-const BUFFER_HEIGHT: usize = 25;
+pub(crate) const BUFFER_WIDTH:  usize = 80;
+pub(crate) const BUFFER_HEIGHT: usize = 25;
 
 
 
@@ -166,8 +166,8 @@ const BUFFER_HEIGHT: usize = 25;
 
 #[repr(transparent)]
 #[derive(Debug, Clone)]
-struct Buffer {
-    chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
+pub(crate) struct Buffer {
+    pub(crate) chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
 /// A writer that writes to the VGA text buffer.